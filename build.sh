@@ -11,6 +11,21 @@ function deploy_device_api {
 	cd device_api && cargo lambda build --release
 	arn=$(cargo lambda deploy | awk -F'function arn:' '{print $2}' | tr -d '\n')
 	give_iam_roles "$arn"
+	# Commands are published to the sensor, cascading delete is run by device_cleanup_lambda
+	role=$(aws lambda get-function --function-name "$arn" | grep Role | awk -F '":' '{printf $2}' | tr "," "\0")
+	aws iam attach-role-policy --role-name "$role" --policy-arn arn:aws:iam::aws:policy/AWSIoTDataAccess
+	aws iam attach-role-policy --role-name "$role" --policy-arn arn:aws:iam::aws:policy/service-role/AWSLambdaRole
+	echo $arn
+}
+
+function deploy_device_cleanup_lambda {
+	cd device_cleanup_lambda && cargo lambda build --release
+	# Deleting every reading of a sensor takes longer than the default timeout
+	arn=$(cargo lambda deploy --timeout 900 | awk -F'function arn:' '{print $2}' | tr -d '\n')
+	give_iam_roles "$arn"
+	# The retained plant config of the sensor is cleared
+	role=$(aws lambda get-function --function-name "$arn" | grep Role | awk -F '":' '{printf $2}' | tr "," "\0")
+	aws iam attach-role-policy --role-name "$role" --policy-arn arn:aws:iam::aws:policy/AWSIoTDataAccess
 	echo $arn
 }
 
@@ -33,3 +48,69 @@ function deploy_wishlist_api {
 function deploy_notification_sender {
 	cd notification_sender && cargo lambda build --release && cargo lambda deploy
 }
+
+function deploy_notification_api {
+	cd notification_api && cargo lambda build --release && cargo lambda deploy
+}
+
+function deploy_stream_aggregation_lambda {
+	cd stream_aggregation_lambda && cargo lambda build --release && cargo lambda deploy
+}
+
+function deploy_offline_detector_lambda {
+	cd offline_detector_lambda && cargo lambda build --release && cargo lambda deploy
+}
+
+function deploy_command_ack_lambda {
+	cd command_ack_lambda && cargo lambda build --release && cargo lambda deploy
+}
+
+function deploy_firmware_api {
+	cd firmware_api && cargo lambda build --release
+	arn=$(cargo lambda deploy | awk -F'function arn:' '{print $2}' | tr -d '\n')
+	give_iam_roles "$arn"
+	# Images are uploaded to S3 with presigned urls, rollouts are sent by firmware_dispatch_lambda
+	role=$(aws lambda get-function --function-name "$arn" | grep Role | awk -F '":' '{printf $2}' | tr "," "\0")
+	aws iam attach-role-policy --role-name "$role" --policy-arn arn:aws:iam::aws:policy/AmazonS3FullAccess
+	aws iam attach-role-policy --role-name "$role" --policy-arn arn:aws:iam::aws:policy/service-role/AWSLambdaRole
+	echo $arn
+}
+
+function deploy_firmware_dispatch_lambda {
+	cd firmware_dispatch_lambda && cargo lambda build --release
+	# Publishing to the whole fleet takes longer than the default timeout
+	arn=$(cargo lambda deploy --timeout 900 | awk -F'function arn:' '{print $2}' | tr -d '\n')
+	give_iam_roles "$arn"
+	# Images are downloaded from S3 with presigned urls, updates are published to the sensors
+	role=$(aws lambda get-function --function-name "$arn" | grep Role | awk -F '":' '{printf $2}' | tr "," "\0")
+	aws iam attach-role-policy --role-name "$role" --policy-arn arn:aws:iam::aws:policy/AmazonS3FullAccess
+	aws iam attach-role-policy --role-name "$role" --policy-arn arn:aws:iam::aws:policy/AWSIoTDataAccess
+	echo $arn
+}
+
+function deploy_firmware_report_lambda {
+	cd firmware_report_lambda && cargo lambda build --release && cargo lambda deploy
+}
+
+function deploy_device_config_api {
+	cd device_config_api && cargo lambda build --release && cargo lambda deploy
+}
+
+function enable_ttl {
+	# Migration for tables created before the retention policies, tables that already have TTL are skipped.
+	# mqtt_month_media_processor enables it only on the tables it creates
+	for table in sensor_telemetry sensor_rollups sensor_aggregates device_commands failed_attempts; do
+		status=$(aws dynamodb describe-time-to-live --table-name "$table" --query 'TimeToLiveDescription.TimeToLiveStatus' --output text)
+		if [ "$status" != "ENABLED" ] && [ "$status" != "ENABLING" ]; then
+			aws dynamodb update-time-to-live --table-name "$table" --time-to-live-specification "Enabled=true,AttributeName=expires_at"
+		fi
+	done
+}
+
+function enable_stream {
+	# Migration for sensor_telemetry created by older deploys or by telemetry_migration, read by stream_aggregation_lambda
+	enabled=$(aws dynamodb describe-table --table-name sensor_telemetry --query 'Table.StreamSpecification.StreamEnabled' --output text)
+	if [ "$enabled" != "True" ]; then
+		aws dynamodb update-table --table-name sensor_telemetry --stream-specification "StreamEnabled=true,StreamViewType=NEW_IMAGE"
+	fi
+}
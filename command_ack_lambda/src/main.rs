@@ -0,0 +1,99 @@
+use aws_config::load_from_env;
+use aws_sdk_dynamodb::{types::AttributeValue, Client};
+use chrono::Utc;
+use helper::command::{CommandStatus, DEVICE_COMMANDS_TABLE};
+use lambda_runtime::{run, service_fn, Error, LambdaEvent};
+use serde::{Deserialize, Serialize};
+
+/*
+    The ESP8266 answers every command on sensor/commands/{device_id}/ack:
+
+    {
+        "command_id": "1697712000123",
+        "success": true,
+        "message": "Pump stopped after 30 seconds"
+    }
+
+    The IoT rule adds the device_id taken from the topic, the IoT policy lets every
+    device publish only on its own topics:
+
+    SELECT *, topic(3) AS device_id FROM 'sensor/commands/+/ack'
+*/
+#[derive(Deserialize)]
+struct Request {
+    device_id: String,
+    command_id: String,
+    success: bool,
+    message: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Response {
+    req_id: String,
+    msg: String,
+}
+
+/// Save the acknowledgment, false if the command doesn't exist or was already acknowledged
+async fn save_ack(client: &Client, ack: &Request) -> Result<bool, Error> {
+    let status = if ack.success { CommandStatus::Acked } else { CommandStatus::Failed };
+    let mut update_expression = "SET #status = :status, acked_at = :now".to_string();
+    let mut request = client
+        .update_item()
+        .table_name(DEVICE_COMMANDS_TABLE)
+        .key("device_id", AttributeValue::S(ack.device_id.clone()))
+        .key("command_id", AttributeValue::S(ack.command_id.clone()))
+        // Late acknowledgments of expired commands are still saved, expired is never written in the table
+        .condition_expression("attribute_exists(command_id) AND #status IN (:pending, :sent)")
+        .expression_attribute_names("#status", "status")
+        .expression_attribute_values(":status", AttributeValue::S(status.as_str().to_string()))
+        .expression_attribute_values(":now", AttributeValue::N(Utc::now().timestamp().to_string()))
+        .expression_attribute_values(":pending", AttributeValue::S(CommandStatus::Pending.as_str().to_string()))
+        .expression_attribute_values(":sent", AttributeValue::S(CommandStatus::Sent.as_str().to_string()));
+    if let Some(message) = &ack.message {
+        update_expression.push_str(", message = :message");
+        request = request.expression_attribute_values(":message", AttributeValue::S(message.clone()));
+    }
+
+    let result = request.update_expression(update_expression).send().await;
+    match result {
+        Ok(_out) => Ok(true),
+        Err(err) => {
+            if err.as_service_error().map(|e| e.is_conditional_check_failed_exception()) == Some(true) {
+                Ok(false)
+            } else {
+                Err(err.into())
+            }
+        }
+    }
+}
+
+async fn function_handler(event: LambdaEvent<Request>) -> Result<Response, Error> {
+    let shared_config = load_from_env().await;
+    let client = Client::new(&shared_config);
+
+    // QoS 1 can deliver the same acknowledgment twice, the second one is ignored
+    let msg = if save_ack(&client, &event.payload).await? {
+        format!("Command {} acknowledged.", event.payload.command_id)
+    } else {
+        format!("Command {} not found or already acknowledged.", event.payload.command_id)
+    };
+
+    let resp = Response {
+        req_id: event.context.request_id,
+        msg,
+    };
+    Ok(resp)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::ERROR)
+        // disable printing the name of the module in every log line.
+        .with_target(false)
+        // disabling time is handy because CloudWatch will add the ingestion time.
+        .without_time()
+        .init();
+
+    run(service_fn(function_handler)).await
+}
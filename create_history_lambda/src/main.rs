@@ -1,112 +1,309 @@
+mod rollup;
+
 use aws_config::load_from_env;
 use aws_sdk_dynamodb::{types::AttributeValue, Client};
 use lambda_runtime::{run, service_fn, Error, LambdaEvent};
 use serde::{Deserialize, Serialize};
-use std::{iter::Iterator, collections::HashMap};
 use tokio_stream::StreamExt;
-use chrono::Utc;
+use chrono::{Duration, NaiveDate, Utc};
+use chrono_tz::Tz;
+use std::env;
+use helper::retention::{get_plan_policy, DataKind, RetentionPolicy, TTL_ATTRIBUTE};
+use rollup::{Period, Rollup, Window};
+
+const TELEMETRY_TABLE: &str = "sensor_telemetry";   // Written by mqtt_month_media_processor
+const ROLLUP_TABLE: &str = "sensor_rollups";        // Keyed by uuid + window_key
+const DEVICE_TABLE: &str = "devices";               // Every registered sensor with its timezone
+const DEADLINE_MARGIN_MS: i64 = 30_000; // Stop a recompute before the lambda timeout and return where to resume
+
+/*
+    Without a command (the nightly schedule) the rollups of yesterday are computed.
+
+    Rebuild the rollups of a date range, e.g. after a change in the rollup logic or when the job failed:
+
+    {
+        "command": "recompute",
+        "sensors": ["297a0620-3b4d-40ed-b407-2216eb0d"],
+        "from": "2023-09-01",
+        "to": "2023-10-15"
+    }
+
+    Without sensors every registered sensor is recomputed. If the lambda is about to time out
+    the response contains `next`, send the same request with `"cursor": <next>` to resume.
+    Rollups are keyed by window, so running the same request twice gives the same result.
+*/
 
 #[derive(Deserialize)]
 struct Request {
-    // command: String,
+    command: Option<String>,
+    sensors: Option<Vec<String>>,
+    from: Option<NaiveDate>,    // Local dates of the sensor, both included
+    to: Option<NaiveDate>,
+    cursor: Option<Cursor>,
+}
+
+/// Where a recompute stopped, the day in the cursor has not been computed yet
+#[derive(Deserialize, Serialize, Clone)]
+struct Cursor {
+    sensor: String,
+    date: NaiveDate,
 }
 
 #[derive(Serialize)]
 struct Response {
     req_id: String,
     msg: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next: Option<Cursor>,
+}
+
+/// Timezone of the devices registered without one, can be changed with the DEFAULT_TIMEZONE environment variable
+fn default_timezone() -> Tz {
+    env::var("DEFAULT_TIMEZONE")
+        .ok()
+        .and_then(|name| name.parse::<Tz>().ok())
+        .unwrap_or(Tz::UTC)
 }
 
-async fn get_all_items(client: &Client, table_name: &str) -> Result<Vec<HashMap<String, AttributeValue>>, Error> {
+/// A registered sensor with its timezone and the owner whose plan sets the retention of its rollups
+struct Sensor {
+    uuid: String,
+    timezone: Tz,
+    owner: String,
+}
+
+/// Every registered sensor, a device can be in the table once per user
+async fn get_sensors(client: &Client) -> Result<Vec<Sensor>, Error> {
     let items: Result<Vec<_>, _> = client
     .scan()
-    .table_name(table_name)
+    .table_name(DEVICE_TABLE)
+    .projection_expression("device_id, user_id, timezone")
+    .into_paginator()
+    .items()
+    .send()
+    .collect()
+    .await;
+
+    let default = default_timezone();
+    let mut sensors: Vec<Sensor> = items?
+        .iter()
+        .filter_map(|item| {
+            let uuid = item.get("device_id")?.as_s().ok()?.clone();
+            let owner = item.get("user_id")?.as_s().ok()?.clone();
+            let timezone = item
+                .get("timezone")
+                .and_then(|v| v.as_s().ok())
+                .and_then(|v| v.parse::<Tz>().ok())
+                .unwrap_or(default);
+            Some(Sensor { uuid, timezone, owner })
+        })
+        .collect();
+    sensors.sort_by(|a, b| a.uuid.cmp(&b.uuid));
+    sensors.dedup_by(|a, b| a.uuid == b.uuid);
+    Ok(sensors)
+}
+
+/// Daily rollup from the raw readings, outliers are left out
+async fn rollup_day(client: &Client, uuid: &str, window: Window, timezone: Tz) -> Result<Rollup, Error> {
+    let mut rollup = Rollup::new(uuid, window, timezone);
+    let start = rollup.window_start();
+    let end = rollup.window_end();
+    let items: Result<Vec<_>, _> = client
+    .query()
+    .table_name(TELEMETRY_TABLE)
+    .key_condition_expression("#uuid = :uuid AND #timestamp BETWEEN :start AND :end")
+    .filter_expression("attribute_not_exists(outlier)")
+    .expression_attribute_names("#uuid", "uuid")
+    .expression_attribute_names("#timestamp", "timestamp")
+    .expression_attribute_values(":uuid", AttributeValue::S(uuid.to_string()))
+    .expression_attribute_values(":start", AttributeValue::N(start.to_string()))
+    .expression_attribute_values(":end", AttributeValue::N((end - 1).to_string()))
     .into_paginator()
     .items()
     .send()
     .collect()
     .await;
-    match items {
-        Ok(items_parsed) => Ok(items_parsed),
-        Err(err) => Err(err.into()),
+
+    for item in items? {
+        rollup.add_reading(&item);
     }
+    Ok(rollup)
 }
 
-async fn delete_item(client: &Client, table_name: &str, value: String) -> bool{
-    let response = client
-    .delete_item()
-    .table_name(table_name)
-    .key(
-        "timestamp",
-        AttributeValue::N(
-            value
-        ),
-    )
+/// Monthly and yearly rollups from the smaller rollups already saved, so we never read the raw readings twice
+async fn rollup_children(client: &Client, uuid: &str, window: Window, timezone: Tz) -> Result<Rollup, Error> {
+    let prefix = window.children_prefix().unwrap();
+    let items: Result<Vec<_>, _> = client
+    .query()
+    .table_name(ROLLUP_TABLE)
+    .key_condition_expression("#uuid = :uuid AND begins_with(window_key, :prefix)")
+    .expression_attribute_names("#uuid", "uuid")
+    .expression_attribute_values(":uuid", AttributeValue::S(uuid.to_string()))
+    .expression_attribute_values(":prefix", AttributeValue::S(prefix))
+    .into_paginator()
+    .items()
     .send()
+    .collect()
     .await;
-    match response {
-        Ok(_response) => true,
-        Err(err) => {
-            println!("{:?}", err);
-            false
+
+    let mut rollup = Rollup::new(uuid, window, timezone);
+    for item in items? {
+        rollup.add_rollup(&item);
+    }
+    Ok(rollup)
+}
+
+/// Save the rollup, the window key is always the same so re-running the job overwrites it
+async fn save_rollup(client: &Client, rollup: &Rollup, retention_policy: &RetentionPolicy, now: i64) -> Result<bool, Error> {
+    if rollup.samples() == 0 {
+        return Ok(false);
+    }
+    let mut item = rollup.to_item(now);
+    let kind = match rollup.window.period {
+        Period::Day => DataKind::Daily,
+        Period::Month | Period::Year => DataKind::Monthly,
+    };
+    if let Some(expires_at) = retention_policy.expires_at(kind, rollup.window_end()) {
+        item.insert(TTL_ATTRIBUTE.to_string(), AttributeValue::N(expires_at.to_string()));
+    }
+    client.put_item()
+    .table_name(ROLLUP_TABLE)
+    .set_item(Some(item))
+    .send()
+    .await?;
+    Ok(true)
+}
+
+/// Remove a rollup left by a previous run when its window has no readings anymore
+async fn delete_rollup(client: &Client, rollup: &Rollup) -> Result<(), Error> {
+    client.delete_item()
+    .table_name(ROLLUP_TABLE)
+    .key("uuid", AttributeValue::S(rollup.uuid.clone()))
+    .key("window_key", AttributeValue::S(rollup.window.key()))
+    .send()
+    .await?;
+    Ok(())
+}
+
+/// Rebuild the rollups of the days of a sensor from start to to, then the months and years
+/// of the whole range from from to to. Returns the first day not computed if the deadline is reached
+#[allow(clippy::too_many_arguments)]
+async fn recompute_sensor(
+    client: &Client,
+    uuid: &str,
+    timezone: Tz,
+    from: NaiveDate,
+    start: NaiveDate,   // Day to resume from, from if the run was not interrupted
+    to: NaiveDate,
+    deadline: i64,
+    retention_policy: &RetentionPolicy,
+) -> Result<Option<NaiveDate>, Error> {
+    let now = Utc::now().timestamp();
+    let mut date = start;
+    while date <= to {
+        if Utc::now().timestamp_millis() + DEADLINE_MARGIN_MS > deadline {
+            return Ok(Some(date));
         }
+        let day = rollup_day(client, uuid, Window::day(date), timezone).await?;
+        if !save_rollup(client, &day, retention_policy, now).await? {
+            delete_rollup(client, &day).await?;
+        }
+        date = date.succ_opt().unwrap();
     }
+
+    // Months and years of the whole range are rebuilt from the saved days, also the ones
+    // whose days were computed before an interrupted run resumed
+    let mut month = Window::month(from);
+    while month.start <= to {
+        let rollup = rollup_children(client, uuid, month, timezone).await?;
+        if !save_rollup(client, &rollup, retention_policy, now).await? {
+            delete_rollup(client, &rollup).await?;
+        }
+        month = Window::month(month.end());
+    }
+    let mut year = Window::year(from);
+    while year.start <= to {
+        let rollup = rollup_children(client, uuid, year, timezone).await?;
+        if !save_rollup(client, &rollup, retention_policy, now).await? {
+            delete_rollup(client, &rollup).await?;
+        }
+        year = Window::year(year.end());
+    }
+    Ok(None)
+}
+
+async fn recompute(client: &Client, request: Request, deadline: i64) -> Result<(String, Option<Cursor>), Error> {
+    let (from, to) = match (request.from, request.to) {
+        (Some(from), Some(to)) if from <= to => (from, to),
+        _ => return Err("recompute needs a valid from and to date".into()),
+    };
+    let mut sensors = get_sensors(client).await?;
+    if let Some(requested) = &request.sensors {
+        sensors.retain(|sensor| requested.contains(&sensor.uuid));
+    }
+    // Sensors are sorted, so the ones before the cursor are already done
+    if let Some(cursor) = &request.cursor {
+        sensors.retain(|sensor| sensor.uuid >= cursor.sensor);
+    }
+
+    let mut recomputed = 0;
+    for Sensor { uuid, timezone, owner } in sensors {
+        let start = match &request.cursor {
+            Some(cursor) if cursor.sensor == uuid => cursor.date.max(from),
+            _ => from,
+        };
+        let retention_policy = get_plan_policy(client, &owner).await;
+        if let Some(date) = recompute_sensor(client, &uuid, timezone, from, start, to, deadline, &retention_policy).await? {
+            let msg = format!("Recomputed {} sensors, stopped at {} of {}.", recomputed, date, uuid);
+            return Ok((msg, Some(Cursor { sensor: uuid, date })));
+        }
+        recomputed += 1;
+    }
+    Ok((format!("Recomputed {} sensors from {} to {}.", recomputed, from, to), None))
 }
+
 async fn function_handler(event: LambdaEvent<Request>) -> Result<Response, Error> {
     let shared_config = load_from_env().await;
     let client = Client::new(&shared_config);
 
-    let req = client.list_tables();
-    let tables = req.send().await.unwrap();
-    for table in tables.table_names().unwrap() {
-        let items = get_all_items(&client, table).await.unwrap();
-        let items_filtered: Vec<_> = items
-            .clone()
-            .into_iter()
-            .filter(|x| x["hour"] == AttributeValue::Bool(true))
-            .collect();
-        if items_filtered.len() == 24 {
-            let mut temp_media: f32 = 0.0;
-            let mut hum_media: f32 = 0.0;
-            for item in items_filtered {
-                temp_media += item["temperature"].as_n().unwrap().parse::<f32>().unwrap();
-                hum_media += item["humidity"].as_n().unwrap().parse::<f32>().unwrap();
-                delete_item(&client, table, item["timestamp"].as_n().unwrap().to_string()).await;
-            }
-            client.put_item()
-            .table_name(table)
-            .item("media_temp", AttributeValue::N(temp_media.to_string()))
-            .item("media_hum", AttributeValue::N(hum_media.to_string()))
-            .item("hour", AttributeValue::Bool(false))
-            .item("media_month", AttributeValue::Bool(true))
-            .item("media_year", AttributeValue::Bool(false))
-            .item("timestamp", AttributeValue::N(Utc::now().timestamp().to_string())).send().await.unwrap();
+    let command = event.payload.command.clone();
+    match command.as_deref() {
+        None | Some("daily") => {}
+        Some("recompute") => {
+            let deadline = event.context.deadline as i64;
+            let (msg, next) = recompute(&client, event.payload, deadline).await?;
+            return Ok(Response {
+                req_id: event.context.request_id,
+                msg,
+                next,
+            });
         }
-        let items_media_filtered: Vec<_> = items.clone()
-        .into_iter()
-        .filter(|x| x["media_month"] == AttributeValue::Bool(true)).collect();
-        if items_media_filtered.len() == 11 {   // one month is missing since we create now
-            let mut media_temp_month = 0.0;
-            let mut media_hum_month = 0.0;
-            for media_month in items_media_filtered {
-                media_temp_month += media_month["media_temp"].as_n().unwrap().parse::<f32>().unwrap();
-                media_hum_month += media_month["media_hum"].as_n().unwrap().parse::<f32>().unwrap();
-            }
-            client.put_item()
-            .table_name(table)
-            .item("media_temp", AttributeValue::N(media_temp_month.to_string()))
-            .item("media_hum", AttributeValue::N(media_hum_month.to_string()))
-            .item("hour", AttributeValue::Bool(false))
-            .item("media_year", AttributeValue::Bool(true))
-            .item("media_month", AttributeValue::Bool(false))
-            .item("timestamp", AttributeValue::N(Utc::now().timestamp().to_string())).send().await.unwrap();
+        Some(command) => return Err(format!("Unknown command {}", command).into()),
+    }
+
+    let now = Utc::now();
+
+    let mut saved = 0;
+    for Sensor { uuid, timezone, owner } in get_sensors(&client).await? {
+        // The last complete day in the calendar of the owner, re-running on the same local day overwrites it
+        let date = now.with_timezone(&timezone).date_naive() - Duration::days(1);
+        let retention_policy = get_plan_policy(&client, &owner).await;
+        let day = rollup_day(&client, &uuid, Window::day(date), timezone).await?;
+        if !save_rollup(&client, &day, &retention_policy, now.timestamp()).await? {
+            continue;   // No readings yesterday, month and year didn't change
         }
+        let month = rollup_children(&client, &uuid, Window::month(date), timezone).await?;
+        save_rollup(&client, &month, &retention_policy, now.timestamp()).await?;
+        let year = rollup_children(&client, &uuid, Window::year(date), timezone).await?;
+        save_rollup(&client, &year, &retention_policy, now.timestamp()).await?;
+        saved += 1;
     }
 
     // Prepare the response
     let resp = Response {
         req_id: event.context.request_id,
-        msg: format!("Command."),
+        msg: format!("Rollups saved for {} sensors.", saved),
+        next: None,
     };
 
     // Return `Response` (it will be serialized to JSON automatically by the runtime)
@@ -124,4 +321,4 @@ async fn main() -> Result<(), Error> {
         .init();
 
     run(service_fn(function_handler)).await
-}
\ No newline at end of file
+}
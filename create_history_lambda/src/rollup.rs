@@ -0,0 +1,218 @@
+use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::{DateTime, Datelike, NaiveDate, TimeZone};
+use chrono_tz::Tz;
+use std::collections::HashMap;
+
+/// Channels we compute statistics for
+pub const CHANNELS: [&str; 3] = ["temperature", "humidity", "soil_humidity"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Period {
+    Day,
+    Month,
+    Year,
+}
+
+impl Period {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Period::Day => "day",
+            Period::Month => "month",
+            Period::Year => "year",
+        }
+    }
+}
+
+/// Calendar window covered by a rollup
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Window {
+    pub period: Period,
+    pub start: NaiveDate,   // First day of the window
+}
+
+impl Window {
+    pub fn day(date: NaiveDate) -> Self {
+        Window { period: Period::Day, start: date }
+    }
+
+    pub fn month(date: NaiveDate) -> Self {
+        Window { period: Period::Month, start: NaiveDate::from_ymd_opt(date.year(), date.month(), 1).unwrap() }
+    }
+
+    pub fn year(date: NaiveDate) -> Self {
+        Window { period: Period::Year, start: NaiveDate::from_ymd_opt(date.year(), 1, 1).unwrap() }
+    }
+
+    /// First day after the window
+    pub fn end(&self) -> NaiveDate {
+        match self.period {
+            Period::Day => self.start.succ_opt().unwrap(),
+            Period::Month if self.start.month() == 12 => NaiveDate::from_ymd_opt(self.start.year() + 1, 1, 1).unwrap(),
+            Period::Month => NaiveDate::from_ymd_opt(self.start.year(), self.start.month() + 1, 1).unwrap(),
+            Period::Year => NaiveDate::from_ymd_opt(self.start.year() + 1, 1, 1).unwrap(),
+        }
+    }
+
+    /// Human readable label, also used as sort key so rollups of a period are sorted by date
+    pub fn label(&self) -> String {
+        match self.period {
+            Period::Day => self.start.format("%Y-%m-%d").to_string(),
+            Period::Month => self.start.format("%Y-%m").to_string(),
+            Period::Year => self.start.format("%Y").to_string(),
+        }
+    }
+
+    /// Sort key of the rollup, the same window always has the same key so re-runs overwrite it
+    pub fn key(&self) -> String {
+        format!("{}#{}", self.period.as_str(), self.label())
+    }
+
+    /// Prefix of the keys of the smaller rollups inside this window (days of a month, months of a year)
+    pub fn children_prefix(&self) -> Option<String> {
+        match self.period {
+            Period::Day => None,
+            Period::Month => Some(format!("{}#{}", Period::Day.as_str(), self.label())),
+            Period::Year => Some(format!("{}#{}", Period::Month.as_str(), self.label())),
+        }
+    }
+}
+
+/// Local midnight of the day, if a DST change skips it the window starts at the first valid instant
+fn local_midnight(date: NaiveDate, timezone: Tz) -> DateTime<Tz> {
+    let midnight = date.and_hms_opt(0, 0, 0).unwrap();
+    match timezone.from_local_datetime(&midnight).earliest() {
+        Some(datetime) => datetime,
+        None => timezone.from_utc_datetime(&midnight),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    pub samples: i64,
+    pub sum: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats { samples: 0, sum: 0.0, min: f64::MAX, max: f64::MIN }
+    }
+}
+
+impl Stats {
+    pub fn add(&mut self, value: f64) {
+        self.samples += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    /// Combine two windows, the mean is weighted by the number of samples
+    pub fn merge(&mut self, other: &Stats) {
+        if other.samples == 0 {
+            return;
+        }
+        self.samples += other.samples;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        if self.samples == 0 {
+            None
+        } else {
+            Some(self.sum / self.samples as f64)
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Rollup {
+    pub uuid: String,
+    pub window: Window,
+    pub timezone: Tz,   // Calendar of the owner, days start at local midnight
+    pub stats: HashMap<String, Stats>,
+}
+
+fn attribute_to_f64(item: &HashMap<String, AttributeValue>, key: &str) -> Option<f64> {
+    item.get(key)
+        .and_then(|v| v.as_n().ok())
+        .and_then(|v| v.parse::<f64>().ok())
+}
+
+impl Rollup {
+    pub fn new(uuid: &str, window: Window, timezone: Tz) -> Self {
+        Rollup {
+            uuid: uuid.to_string(),
+            window,
+            timezone,
+            stats: HashMap::new(),
+        }
+    }
+
+    /// Unix timestamp of the first second of the window
+    pub fn window_start(&self) -> i64 {
+        local_midnight(self.window.start, self.timezone).timestamp()
+    }
+
+    /// Unix timestamp of the first second after the window
+    pub fn window_end(&self) -> i64 {
+        local_midnight(self.window.end(), self.timezone).timestamp()
+    }
+
+    pub fn samples(&self) -> i64 {
+        self.stats.values().map(|v| v.samples).max().unwrap_or(0)
+    }
+
+    /// Add a raw reading of the telemetry table
+    pub fn add_reading(&mut self, item: &HashMap<String, AttributeValue>) {
+        for channel in CHANNELS {
+            if let Some(value) = attribute_to_f64(item, channel) {
+                self.stats.entry(channel.to_string()).or_default().add(value);
+            }
+        }
+    }
+
+    /// Add a smaller rollup saved by a previous run
+    pub fn add_rollup(&mut self, item: &HashMap<String, AttributeValue>) {
+        for channel in CHANNELS {
+            let samples = attribute_to_f64(item, &format!("{}_samples", channel)).unwrap_or(0.0) as i64;
+            let mean = attribute_to_f64(item, &format!("{}_mean", channel));
+            let min = attribute_to_f64(item, &format!("{}_min", channel));
+            let max = attribute_to_f64(item, &format!("{}_max", channel));
+            if let (Some(mean), Some(min), Some(max)) = (mean, min, max) {
+                let other = Stats { samples, sum: mean * samples as f64, min, max };
+                self.stats.entry(channel.to_string()).or_default().merge(&other);
+            }
+        }
+    }
+
+    pub fn to_item(&self, computed_at: i64) -> HashMap<String, AttributeValue> {
+        let mut item = HashMap::new();
+        item.insert("uuid".to_string(), AttributeValue::S(self.uuid.clone()));
+        item.insert("window_key".to_string(), AttributeValue::S(self.window.key()));
+        item.insert("period".to_string(), AttributeValue::S(self.window.period.as_str().to_string()));
+        item.insert("label".to_string(), AttributeValue::S(self.window.label()));
+        item.insert("window_start".to_string(), AttributeValue::N(self.window_start().to_string()));
+        item.insert("window_end".to_string(), AttributeValue::N(self.window_end().to_string()));
+        // Same instants with the local offset, so the app can put them on the chart as they are
+        let start_local = local_midnight(self.window.start, self.timezone).to_rfc3339();
+        let end_local = local_midnight(self.window.end(), self.timezone).to_rfc3339();
+        item.insert("window_start_local".to_string(), AttributeValue::S(start_local));
+        item.insert("window_end_local".to_string(), AttributeValue::S(end_local));
+        item.insert("timezone".to_string(), AttributeValue::S(self.timezone.name().to_string()));
+        item.insert("computed_at".to_string(), AttributeValue::N(computed_at.to_string()));
+
+        for (channel, stats) in self.stats.iter() {
+            if let Some(mean) = stats.mean() {
+                item.insert(format!("{}_samples", channel), AttributeValue::N(stats.samples.to_string()));
+                item.insert(format!("{}_mean", channel), AttributeValue::N(mean.to_string()));
+                item.insert(format!("{}_min", channel), AttributeValue::N(stats.min.to_string()));
+                item.insert(format!("{}_max", channel), AttributeValue::N(stats.max.to_string()));
+            }
+        }
+        item
+    }
+}
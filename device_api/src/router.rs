@@ -1,14 +1,36 @@
-use lambda_http::{Request, Response, Body, Error};
+use lambda_http::{Request, RequestExt, Response, Body, Error};
 use aws_sdk_dynamodb::{Client};
+mod claim;
+mod command;
+mod device;
 mod endpoints;
+mod sharing;
 use endpoints::get_devices;
-use self::endpoints::{not_implemented, add_devices, delete_devices};
+use self::endpoints::{not_implemented, add_devices, delete_devices, get_device_stats, update_device};
+use self::endpoints::{share_device, revoke_access, get_device_grants, get_shared_devices};
+use self::endpoints::{send_command, get_device_commands, put_device_secret};
 
 pub async fn router(request: Request, client:  &Client) -> Result<Response<Body>, Error>{
     match request.method().as_str() {
+        "GET" if has_param(&request, "shared") => get_shared_devices(request, &client).await,
+        "GET" if has_param(&request, "commands") => get_device_commands(request, &client).await,
+        "GET" if has_param(&request, "grants") => get_device_grants(request, &client).await,
+        "GET" if has_param(&request, "device_id") => get_device_stats(request, &client).await,
         "GET" => get_devices(request, &client).await,
+        "POST" if has_param(&request, "commands") => send_command(request, &client).await,
+        "POST" if has_param(&request, "grantee") => share_device(request, &client).await,
         "POST" => add_devices(request, &client).await,
+        "PATCH" => update_device(request, &client).await,
+        "PUT" if has_param(&request, "secret") => put_device_secret(request, &client).await,
+        "DELETE" if has_param(&request, "grantee") => revoke_access(request, &client).await,
         "DELETE" => delete_devices(request, &client).await,
         _ => not_implemented(),
     }
 }
+
+fn has_param(request: &Request, name: &str) -> bool {
+    request
+        .query_string_parameters_ref()
+        .and_then(|params| params.first(name))
+        .is_some()
+}
@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::env;
+
+use aws_sdk_dynamodb::types::{AttributeValue, Delete, Put, TransactWriteItem, Update};
+use aws_sdk_dynamodb::Client;
+use aws_sdk_lambda::primitives::Blob;
+use aws_sdk_lambda::types::InvocationType;
+use chrono::Utc;
+use helper::claim::{hash_claim_code, CLAIM_CODE_HMAC_ATTRIBUTE, DEVICE_REGISTRY_TABLE, MAX_FAILED_CLAIMS};
+use helper::device_auth::{hash_device_secret, SECRET_HASH_ATTRIBUTE};
+use helper::retention::TTL_ATTRIBUTE;
+use helper::throttle::{attempt_expires_at, attempt_key, FAILED_ATTEMPTS_TABLE};
+use lambda_http::Error;
+use serde::Serialize;
+
+use super::device::DeviceStatus;
+
+/*
+    Every ESP8266 is added to the registry at manufacturing:
+
+    {
+        "device_id": "297a0620-3b4d-40ed-b407-2216eb0d",
+        "claim_code_hmac": "<HMAC-SHA256 of the code printed on the box>",
+        "hardware_model": "ESP8266"
+    }
+
+    The first user presenting the claim code becomes the owner. To give the device to
+    someone else the owner must release it, then the new owner claims it with the same code.
+*/
+
+const DEVICE_TABLE_NAME: &str = "devices";
+
+#[derive(Debug, PartialEq)]
+pub enum ClaimResult {
+    Claimed,
+    AlreadyOwned,   // Claimed again by the owner, nothing changed
+    NotFound,       // Not in the registry, the device doesn't exist
+    InvalidCode,
+    Conflict,       // Another user owns the device
+    TooManyAttempts,    // Too many wrong codes from the user or for the device, retry in the next window
+}
+
+/// Failed claims counted in the current window
+async fn get_failed_claims(client: &Client, key: &str) -> Result<i64, Error> {
+    let result = client
+        .get_item()
+        .table_name(FAILED_ATTEMPTS_TABLE)
+        .key("attempt_key", AttributeValue::S(key.to_string()))
+        .send()
+        .await?;
+    Ok(result
+        .item()
+        .and_then(|item| item.get("attempts"))
+        .and_then(|v| v.as_n().ok())
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(0))
+}
+
+async fn count_failed_claim(client: &Client, key: &str, now: i64) -> Result<(), Error> {
+    client
+        .update_item()
+        .table_name(FAILED_ATTEMPTS_TABLE)
+        .key("attempt_key", AttributeValue::S(key.to_string()))
+        .update_expression(format!("ADD attempts :one SET {} = :expires_at", TTL_ATTRIBUTE))
+        .expression_attribute_values(":one", AttributeValue::N("1".to_string()))
+        .expression_attribute_values(":expires_at", AttributeValue::N(attempt_expires_at(now).to_string()))
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// True if the claim code matches the one of the registry
+fn is_valid_code(device: &HashMap<String, AttributeValue>, claim_code: &str) -> bool {
+    device
+        .get(CLAIM_CODE_HMAC_ATTRIBUTE)
+        .and_then(|v| v.as_s().ok())
+        .map(|hmac| *hmac == hash_claim_code(claim_code))
+        .unwrap_or(false)
+}
+
+/// Claim codes are short, wrong codes are counted per user and per device so they can't be guessed
+pub async fn claim_device(
+    client: &Client,
+    user_id: &str,
+    device_id: &str,
+    claim_code: &str,
+    timezone: Option<&str>,
+) -> Result<ClaimResult, Error> {
+    let now = Utc::now().timestamp();
+    let user_key = attempt_key("claim_user", user_id, now);
+    let device_key = attempt_key("claim_device", device_id, now);
+    if get_failed_claims(client, &user_key).await? >= MAX_FAILED_CLAIMS
+        || get_failed_claims(client, &device_key).await? >= MAX_FAILED_CLAIMS
+    {
+        return Ok(ClaimResult::TooManyAttempts);
+    }
+
+    let registry = client
+        .get_item()
+        .table_name(DEVICE_REGISTRY_TABLE)
+        .key("device_id", AttributeValue::S(device_id.to_string()))
+        .send()
+        .await?;
+
+    let device = match registry.item() {
+        Some(device) => device,
+        None => {
+            count_failed_claim(client, &user_key, now).await?;     // Guessing device ids is also an attempt
+            return Ok(ClaimResult::NotFound);
+        }
+    };
+    if !is_valid_code(device, claim_code) {
+        count_failed_claim(client, &user_key, now).await?;
+        count_failed_claim(client, &device_key, now).await?;
+        return Ok(ClaimResult::InvalidCode);
+    }
+    match device.get("owner").and_then(|v| v.as_s().ok()) {
+        Some(owner) if owner == user_id => return Ok(ClaimResult::AlreadyOwned),
+        Some(_) => return Ok(ClaimResult::Conflict),
+        None => {}
+    }
+
+    let now = AttributeValue::N(now.to_string());
+    // Only one of two concurrent claims can set the owner, the other transaction is canceled
+    let set_owner = Update::builder()
+        .table_name(DEVICE_REGISTRY_TABLE)
+        .key("device_id", AttributeValue::S(device_id.to_string()))
+        .update_expression("SET #owner = :owner, claimed_at = :now")
+        .condition_expression("attribute_not_exists(#owner)")
+        .expression_attribute_names("#owner", "owner")
+        .expression_attribute_values(":owner", AttributeValue::S(user_id.to_string()))
+        .expression_attribute_values(":now", now.clone())
+        .build();
+
+    let mut put_device = Put::builder()
+        .table_name(DEVICE_TABLE_NAME)
+        .item("user_id", AttributeValue::S(user_id.to_string()))
+        .item("device_id", AttributeValue::S(device_id.to_string()))
+        .item("created_at", now)
+        .item("status", AttributeValue::S(DeviceStatus::Provisioning.as_str().to_string()));
+    if let Some(hardware_model) = device.get("hardware_model") {
+        put_device = put_device.item("hardware_model", hardware_model.clone());
+    }
+    if let Some(timezone) = timezone {
+        put_device = put_device.item("timezone", AttributeValue::S(timezone.to_string()));
+    }
+
+    let result = client
+        .transact_write_items()
+        .transact_items(TransactWriteItem::builder().update(set_owner).build())
+        .transact_items(TransactWriteItem::builder().put(put_device.build()).build())
+        .send()
+        .await;
+
+    match result {
+        Ok(_out) => Ok(ClaimResult::Claimed),
+        Err(err) => {
+            if err.as_service_error().map(|e| e.is_transaction_canceled_exception()) == Some(true) {
+                Ok(ClaimResult::Conflict)
+            } else {
+                Err(err.into())
+            }
+        }
+    }
+}
+
+/// Remove the device from the account of the owner so it can be claimed again, false if the user doesn't own it
+pub async fn release_device(client: &Client, user_id: &str, device_id: &str) -> Result<bool, Error> {
+    let delete_device = Delete::builder()
+        .table_name(DEVICE_TABLE_NAME)
+        .key("user_id", AttributeValue::S(user_id.to_string()))
+        .key("device_id", AttributeValue::S(device_id.to_string()))
+        .condition_expression("attribute_exists(device_id)")
+        .build();
+
+    let registry = client
+        .get_item()
+        .table_name(DEVICE_REGISTRY_TABLE)
+        .key("device_id", AttributeValue::S(device_id.to_string()))
+        .send()
+        .await?;
+
+    let mut request = client
+        .transact_write_items()
+        .transact_items(TransactWriteItem::builder().delete(delete_device).build());
+
+    // Devices added before the registry have only the row in the devices table
+    if registry.item().is_some() {
+        let remove_owner = Update::builder()
+            .table_name(DEVICE_REGISTRY_TABLE)
+            .key("device_id", AttributeValue::S(device_id.to_string()))
+            .update_expression("REMOVE #owner, claimed_at")
+            .condition_expression("#owner = :owner")
+            .expression_attribute_names("#owner", "owner")
+            .expression_attribute_values(":owner", AttributeValue::S(user_id.to_string()))
+            .build();
+        request = request.transact_items(TransactWriteItem::builder().update(remove_owner).build());
+    }
+
+    match request.send().await {
+        Ok(_out) => Ok(true),
+        Err(err) => {
+            if err.as_service_error().map(|e| e.is_transaction_canceled_exception()) == Some(true) {
+                Ok(false)
+            } else {
+                Err(err.into())
+            }
+        }
+    }
+}
+
+/// Save the hash of a new secret of the device, false if the device is not in the registry.
+/// The old secret stops working, the new one must be flashed on the ESP8266
+pub async fn set_device_secret(client: &Client, device_id: &str, secret: &str) -> Result<bool, Error> {
+    let result = client
+        .update_item()
+        .table_name(DEVICE_REGISTRY_TABLE)
+        .key("device_id", AttributeValue::S(device_id.to_string()))
+        .update_expression(format!("SET {} = :hash, secret_updated_at = :now", SECRET_HASH_ATTRIBUTE))
+        .condition_expression("attribute_exists(device_id)")
+        .expression_attribute_values(":hash", AttributeValue::S(hash_device_secret(secret)))
+        .expression_attribute_values(":now", AttributeValue::N(Utc::now().timestamp().to_string()))
+        .send()
+        .await;
+
+    match result {
+        Ok(_out) => Ok(true),
+        Err(err) => {
+            if err.as_service_error().map(|e| e.is_conditional_check_failed_exception()) == Some(true) {
+                Ok(false)
+            } else {
+                Err(err.into())
+            }
+        }
+    }
+}
+
+/// Event of device_cleanup_lambda
+#[derive(Serialize)]
+struct CleanupRequest<'a> {
+    user_id: &'a str,
+    device_id: &'a str,
+}
+
+/// Name of the device_cleanup_lambda, can be changed with the DEVICE_CLEANUP_FUNCTION environment variable
+fn cleanup_function() -> String {
+    env::var("DEVICE_CLEANUP_FUNCTION").unwrap_or("device_cleanup_lambda".to_string())
+}
+
+/// Flag the device as deleting, false if the user doesn't own it
+pub async fn mark_deleting(client: &Client, user_id: &str, device_id: &str) -> Result<bool, Error> {
+    let result = client
+        .update_item()
+        .table_name(DEVICE_TABLE_NAME)
+        .key("user_id", AttributeValue::S(user_id.to_string()))
+        .key("device_id", AttributeValue::S(device_id.to_string()))
+        .update_expression("SET #status = :deleting")
+        .condition_expression("attribute_exists(device_id)")
+        .expression_attribute_names("#status", "status")
+        .expression_attribute_values(":deleting", AttributeValue::S(DeviceStatus::Deleting.as_str().to_string()))
+        .send()
+        .await;
+
+    match result {
+        Ok(_out) => Ok(true),
+        Err(err) => {
+            if err.as_service_error().map(|e| e.is_conditional_check_failed_exception()) == Some(true) {
+                Ok(false)
+            } else {
+                Err(err.into())
+            }
+        }
+    }
+}
+
+/// Ask device_cleanup_lambda to remove the data of the device and release it, without waiting for it
+pub async fn start_cleanup(user_id: &str, device_id: &str) -> Result<(), Error> {
+    let shared_config = aws_config::load_from_env().await;
+    let lambda_client = aws_sdk_lambda::Client::new(&shared_config);
+    lambda_client
+        .invoke()
+        .function_name(cleanup_function())
+        .invocation_type(InvocationType::Event)
+        .payload(Blob::new(serde_json::to_string(&CleanupRequest { user_id, device_id }).unwrap()))
+        .send()
+        .await?;
+    Ok(())
+}
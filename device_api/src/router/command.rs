@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::Client;
+use aws_sdk_iotdataplane::primitives::Blob;
+use chrono::Utc;
+use helper::command::{command_topic, Command, CommandStatus, COMMAND_TIMEOUT, DEVICE_COMMANDS_TABLE};
+use helper::retention::TTL_ATTRIBUTE;
+use lambda_http::Error;
+use serde::Serialize;
+
+const COMMAND_RETENTION_DAYS: i64 = 30;
+const CREATE_RETRIES: i64 = 5;  // Two commands sent to the same device in the same millisecond
+
+/// A command sent to the device, acknowledgments are saved by command_ack_lambda
+#[derive(Serialize, Clone, Debug)]
+pub struct CommandRecord {
+    pub device_id: String,
+    pub command_id: String,     // Milliseconds timestamp, newer commands have greater ids
+    pub command: Option<Command>,
+    pub status: CommandStatus,
+    pub issued_by: Option<String>,
+    pub created_at: Option<i64>,
+    pub sent_at: Option<i64>,
+    pub acked_at: Option<i64>,
+    pub message: Option<String>,    // Reported by the device with the acknowledgment
+}
+
+#[derive(Serialize)]
+struct CommandMessage<'a> {
+    command_id: &'a str,
+    #[serde(flatten)]
+    command: &'a Command,
+}
+
+impl From<&HashMap<String, AttributeValue>> for CommandRecord {
+    fn from(item: &HashMap<String, AttributeValue>) -> Self {
+        let string = |key: &str| item.get(key).and_then(|v| v.as_s().ok()).map(|v| v.to_string());
+        let number = |key: &str| {
+            item.get(key)
+                .and_then(|v| v.as_n().ok())
+                .and_then(|v| v.parse::<i64>().ok())
+        };
+
+        let created_at = number("created_at");
+        let mut status = string("status")
+            .and_then(|v| CommandStatus::parse(&v))
+            .unwrap_or(CommandStatus::Pending);
+        // The device never answered, it was probably offline
+        let waiting_since = number("sent_at").or(created_at).unwrap_or_default();
+        if (status == CommandStatus::Pending || status == CommandStatus::Sent)
+            && Utc::now().timestamp() - waiting_since > COMMAND_TIMEOUT
+        {
+            status = CommandStatus::Expired;
+        }
+
+        CommandRecord {
+            device_id: string("device_id").unwrap_or_default(),
+            command_id: string("command_id").unwrap_or_default(),
+            command: string("command").and_then(|v| serde_json::from_str(&v).ok()),
+            status,
+            issued_by: string("issued_by"),
+            created_at,
+            sent_at: number("sent_at"),
+            acked_at: number("acked_at"),
+            message: string("message"),
+        }
+    }
+}
+
+/// Save the command as pending, the id is unique for the device
+pub async fn create_command(client: &Client, device_id: &str, issued_by: &str, command: &Command) -> Result<CommandRecord, Error> {
+    let now = Utc::now();
+    let expires_at = now.timestamp() + COMMAND_RETENTION_DAYS * 24 * 60 * 60;
+
+    for retry in 0..CREATE_RETRIES {
+        let command_id = format!("{:013}", now.timestamp_millis() + retry);
+        let result = client
+            .put_item()
+            .table_name(DEVICE_COMMANDS_TABLE)
+            .item("device_id", AttributeValue::S(device_id.to_string()))
+            .item("command_id", AttributeValue::S(command_id.clone()))
+            .item("command", AttributeValue::S(serde_json::to_string(command).unwrap()))
+            .item("status", AttributeValue::S(CommandStatus::Pending.as_str().to_string()))
+            .item("issued_by", AttributeValue::S(issued_by.to_string()))
+            .item("created_at", AttributeValue::N(now.timestamp().to_string()))
+            .item(TTL_ATTRIBUTE, AttributeValue::N(expires_at.to_string()))
+            .condition_expression("attribute_not_exists(command_id)")
+            .send()
+            .await;
+
+        match result {
+            Ok(_out) => {
+                return Ok(CommandRecord {
+                    device_id: device_id.to_string(),
+                    command_id,
+                    command: Some(command.clone()),
+                    status: CommandStatus::Pending,
+                    issued_by: Some(issued_by.to_string()),
+                    created_at: Some(now.timestamp()),
+                    sent_at: None,
+                    acked_at: None,
+                    message: None,
+                })
+            }
+            Err(err) => {
+                if err.as_service_error().map(|e| e.is_conditional_check_failed_exception()) != Some(true) {
+                    return Err(err.into());
+                }
+            }
+        }
+    }
+    Err(format!("Cannot create a command id for {}", device_id).into())
+}
+
+/// Publish the command to the device, not retained so it is not executed again at the next connection
+pub async fn publish_command(
+    iot_data_client: &aws_sdk_iotdataplane::Client,
+    record: &CommandRecord,
+    command: &Command,
+) -> bool {
+    let message = CommandMessage {
+        command_id: &record.command_id,
+        command,
+    };
+    let result = iot_data_client
+        .publish()
+        .topic(command_topic(&record.device_id))
+        .qos(1)
+        .retain(false)
+        .payload(Blob::new(serde_json::to_string(&message).unwrap()))
+        .send()
+        .await;
+
+    match result {
+        Ok(_out) => true,
+        Err(err) => {
+            println!("{:?}", err);
+            false
+        }
+    }
+}
+
+/// Move a pending command to sent or failed, an acknowledgment may already have arrived
+pub async fn set_status(client: &Client, record: &mut CommandRecord, status: CommandStatus) -> Result<(), Error> {
+    let now = Utc::now().timestamp();
+    let result = client
+        .update_item()
+        .table_name(DEVICE_COMMANDS_TABLE)
+        .key("device_id", AttributeValue::S(record.device_id.clone()))
+        .key("command_id", AttributeValue::S(record.command_id.clone()))
+        .update_expression("SET #status = :status, sent_at = :now")
+        .condition_expression("#status = :pending")
+        .expression_attribute_names("#status", "status")
+        .expression_attribute_values(":status", AttributeValue::S(status.as_str().to_string()))
+        .expression_attribute_values(":pending", AttributeValue::S(CommandStatus::Pending.as_str().to_string()))
+        .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+        .send()
+        .await;
+
+    match result {
+        Ok(_out) => {
+            record.status = status;
+            record.sent_at = Some(now);
+            Ok(())
+        }
+        Err(err) => {
+            if err.as_service_error().map(|e| e.is_conditional_check_failed_exception()) == Some(true) {
+                Ok(())
+            } else {
+                Err(err.into())
+            }
+        }
+    }
+}
+
+pub async fn get_command(client: &Client, device_id: &str, command_id: &str) -> Result<Option<CommandRecord>, Error> {
+    let result = client
+        .get_item()
+        .table_name(DEVICE_COMMANDS_TABLE)
+        .key("device_id", AttributeValue::S(device_id.to_string()))
+        .key("command_id", AttributeValue::S(command_id.to_string()))
+        .send()
+        .await?;
+    Ok(result.item().map(CommandRecord::from))
+}
+
+/// Commands of the device, newest first
+pub async fn get_commands(
+    client: &Client,
+    device_id: &str,
+    limit: i32,
+    next: Option<&str>,
+) -> Result<(Vec<CommandRecord>, Option<String>), Error> {
+    let mut request = client
+        .query()
+        .table_name(DEVICE_COMMANDS_TABLE)
+        .key_condition_expression("#device_id = :device_id")
+        .expression_attribute_names("#device_id", "device_id")
+        .expression_attribute_values(":device_id", AttributeValue::S(device_id.to_string()))
+        .scan_index_forward(false)
+        .limit(limit);
+    if let Some(next) = next {
+        request = request
+            .exclusive_start_key("device_id", AttributeValue::S(device_id.to_string()))
+            .exclusive_start_key("command_id", AttributeValue::S(next.to_string()));
+    }
+
+    let results = request.send().await?;
+    let commands = results
+        .items()
+        .unwrap_or_default()
+        .iter()
+        .map(CommandRecord::from)
+        .collect();
+    let next = results
+        .last_evaluated_key()
+        .and_then(|key| key.get("command_id"))
+        .and_then(|v| v.as_s().ok())
+        .map(|v| v.to_string());
+    Ok((commands, next))
+}
@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use serde::{Deserialize, Serialize};
+
+/// Lifecycle of a device, from the first registration to the dismission
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceStatus {
+    #[default]
+    Provisioning,   // Registered, no reading received yet
+    Active,
+    Offline,        // No reading for a while
+    Retired,        // Dismissed by the user, kept for the history
+    Deleting,       // Its data is being removed by device_cleanup_lambda
+}
+
+impl DeviceStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeviceStatus::Provisioning => "provisioning",
+            DeviceStatus::Active => "active",
+            DeviceStatus::Offline => "offline",
+            DeviceStatus::Retired => "retired",
+            DeviceStatus::Deleting => "deleting",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "provisioning" => Some(DeviceStatus::Provisioning),
+            "active" => Some(DeviceStatus::Active),
+            "offline" => Some(DeviceStatus::Offline),
+            "retired" => Some(DeviceStatus::Retired),
+            "deleting" => Some(DeviceStatus::Deleting),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Device {
+    pub device_id: String,
+    pub name: Option<String>,
+    pub location: Option<String>,           // Greenhouse or room chosen by the user
+    pub firmware_version: Option<String>,   // Sent by the ESP8266 with the readings
+    pub hardware_model: Option<String>,
+    pub created_at: Option<i64>,
+    pub last_seen: Option<i64>,             // Timestamp of the last reading
+    pub timezone: Option<String>,           // IANA name, history days and months are computed in it
+    pub status: DeviceStatus,
+}
+
+fn attribute_to_string(item: &HashMap<String, AttributeValue>, key: &str) -> Option<String> {
+    item.get(key).and_then(|v| v.as_s().ok()).map(|v| v.to_string())
+}
+
+fn attribute_to_i64(item: &HashMap<String, AttributeValue>, key: &str) -> Option<i64> {
+    item.get(key)
+        .and_then(|v| v.as_n().ok())
+        .and_then(|v| v.parse::<i64>().ok())
+}
+
+impl From<&HashMap<String, AttributeValue>> for Device {
+    fn from(item: &HashMap<String, AttributeValue>) -> Self {
+        Device {
+            device_id: attribute_to_string(item, "device_id").unwrap_or_default(),
+            name: attribute_to_string(item, "name"),
+            location: attribute_to_string(item, "location"),
+            firmware_version: attribute_to_string(item, "firmware_version"),
+            hardware_model: attribute_to_string(item, "hardware_model"),
+            created_at: attribute_to_i64(item, "created_at"),
+            last_seen: attribute_to_i64(item, "last_seen"),
+            timezone: attribute_to_string(item, "timezone"),
+            // Devices added before the registry have no status, they already sent readings
+            status: attribute_to_string(item, "status")
+                .and_then(|v| DeviceStatus::parse(&v))
+                .unwrap_or(DeviceStatus::Active),
+        }
+    }
+}
+
+/// Body of PATCH, only the fields chosen by the user can be edited
+#[derive(Deserialize, Debug, Default)]
+pub struct DeviceUpdate {
+    pub name: Option<String>,
+    pub location: Option<String>,
+    pub status: Option<DeviceStatus>,   // Only retired, or active to bring a retired device back
+    pub timezone: Option<String>,       // IANA name, e.g. Europe/Rome, used by the next rollups
+}
+
+impl DeviceUpdate {
+    pub fn is_empty(&self) -> bool {
+        self.name.is_none() && self.location.is_none() && self.status.is_none() && self.timezone.is_none()
+    }
+}
@@ -1,12 +1,23 @@
-use std::collections::HashMap;
-
-
 use aws_sdk_dynamodb::{types::AttributeValue, Client};
 use lambda_http::{Body, Error, Request, RequestExt, Response};
 use serde::{Deserialize, Serialize};
 use helper::get_user_id;
+use chrono::Utc;
+use chrono_tz::Tz;
+use super::claim::{claim_device, mark_deleting, release_device, set_device_secret, start_cleanup, ClaimResult};
+use super::command::{create_command, get_command, get_commands, publish_command, set_status, CommandRecord};
+use super::device::{Device, DeviceStatus, DeviceUpdate};
+use super::sharing::{delete_grant, delete_grants, get_access, get_grants, get_shared_with, put_grant, Grant};
+use helper::admin::is_admin;
+use helper::command::{Command, CommandStatus};
+use helper::device_auth::MIN_SECRET_LENGTH;
+use rand::Rng;
+use helper::sharing::Role;
 
 const DEVICE_TABLE_NAME: &str = "devices";
+const DEVICE_STATS_TABLE_NAME: &str = "device_stats"; // Written by mqtt_month_media_processor
+const DEFAULT_PAGE_SIZE: i32 = 20;
+const MAX_PAGE_SIZE: i32 = 100;
 
 #[derive(Serialize, Deserialize)]
 struct ResponseBody<'a> {
@@ -14,48 +25,118 @@ struct ResponseBody<'a> {
     message: &'a str,
 }
 
+/// Body of PUT with secret, the secret is generated when missing
+#[derive(Deserialize, Default)]
+struct SecretRequest {
+    device_secret: Option<String>,
+}
+
+/// Returned once to the admin, only the hash is saved
+#[derive(Serialize)]
+struct SecretResponse {
+    device_id: String,
+    device_secret: String,
+}
+
 #[derive(Serialize, Deserialize)]
-struct Item {
-    user_id: String,
+struct DeviceStats {
     device_id: String,
+    rejected_readings: i64, // Readings discarded at ingest because physically impossible
+    last_rejected: Option<i64>,
 }
 
-fn query_to_string(items: &[HashMap<String, AttributeValue>]) -> String {
-    let mut items_parsed = Vec::new();
-    for item_raw in items {
-        for element in item_raw {
-            let item = Item {
-                user_id: element.0.to_string(),
-                device_id: element.1.as_s().unwrap().to_string(),
-            };
-            items_parsed.push(item);
-        }
-    } 
-    serde_json::to_string(&items_parsed).unwrap()
+#[derive(Serialize)]
+struct Page {
+    items: Vec<Device>,
+    next: Option<String>,   // Pass it as "next" to get the following page
+}
+
+#[derive(Serialize)]
+struct CommandPage {
+    items: Vec<CommandRecord>,
+    next: Option<String>,
+}
+
+fn message_response(status: u16, error: bool, message: &str) -> Result<Response<Body>, Error> {
+    let response_body = ResponseBody { error, message };
+
+    let response = Response::builder()
+        .status(status)
+        .header("content-type", "text/html")
+        .body(serde_json::to_string(&response_body).unwrap().into())
+        .map_err(Box::new)?;
+    Ok(response)
 }
 
 pub async fn get_devices(req: Request, client: &Client) -> Result<Response<Body>, Error> {
     let user_id = get_user_id(&req);
+    let params = req.query_string_parameters_ref();
+
+    let limit = params
+        .and_then(|params| params.first("limit"))
+        .and_then(|v| v.parse::<i32>().ok())
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let next = params.and_then(|params| params.first("next"));
+    let status = match params.and_then(|params| params.first("status")) {
+        Some(value) => match DeviceStatus::parse(value) {
+            Some(status) => Some(status),
+            None => return message_response(400, true, "Invalid status"),
+        },
+        None => None,
+    };
 
-    let results = client
+    let mut request = client
         .query()
         .table_name(DEVICE_TABLE_NAME)
         .key_condition_expression("#key = :value".to_string())
         .expression_attribute_names("#key", "user_id")
-        .expression_attribute_values(":value", AttributeValue::S(String::from(user_id)))
-        .send()
-        .await
-        .unwrap();
- 
+        .expression_attribute_values(":value", AttributeValue::S(user_id.clone()))
+        .limit(limit);
+
+    if let Some(next) = next {
+        request = request
+            .exclusive_start_key("user_id", AttributeValue::S(user_id))
+            .exclusive_start_key("device_id", AttributeValue::S(next.to_string()));
+    }
+    if let Some(status) = status {
+        // Applied after limit, so a page can contain less than limit items.
+        // Devices added before the registry have no status and are active.
+        let filter = if status == DeviceStatus::Active {
+            "#status = :status OR attribute_not_exists(#status)"
+        } else {
+            "#status = :status"
+        };
+        request = request
+            .filter_expression(filter)
+            .expression_attribute_names("#status", "status")
+            .expression_attribute_values(":status", AttributeValue::S(status.as_str().to_string()));
+    }
+
+    let results = request.send().await?;
+
+    let page = Page {
+        items: results
+            .items()
+            .unwrap_or_default()
+            .iter()
+            .map(Device::from)
+            .collect(),
+        next: results
+            .last_evaluated_key()
+            .and_then(|key| key.get("device_id"))
+            .map(|v| v.as_s().unwrap().to_string()),
+    };
+
     let response = Response::builder()
         .status(200)
-        .header("content-type", "text/html")
-        .body(query_to_string(results.items().unwrap()).into())
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&page).unwrap().into())
         .map_err(Box::new)?;
     Ok(response)
 }
 
-pub async fn add_devices(req: Request, client: &Client) -> Result<Response<Body>, Error> {
+pub async fn get_device_stats(req: Request, client: &Client) -> Result<Response<Body>, Error> {
     let user_id = get_user_id(&req);
 
     let board_uuid = req
@@ -63,33 +144,72 @@ pub async fn add_devices(req: Request, client: &Client) -> Result<Response<Body>
         .and_then(|params| params.first("device_id"))
         .unwrap();
 
-    let user_av = AttributeValue::S(user_id.to_string());
-    let board_av = AttributeValue::S(board_uuid.to_string());
+    if get_access(client, &user_id, board_uuid).await?.is_none() {   // Owner and shared users can see the stats
+        return message_response(404, true, "Device not found");
+    }
 
-    let request = client
-        .put_item()
-        .table_name(DEVICE_TABLE_NAME)
-        .item("user_id", user_av)
-        .item("device_id", board_av);
+    let stats = client
+        .get_item()
+        .table_name(DEVICE_STATS_TABLE_NAME)
+        .key("device_id", AttributeValue::S(board_uuid.to_string()))
+        .send()
+        .await?;
 
-    let resp = request.send().await;
+    let number = |key: &str| {
+        stats
+            .item()
+            .and_then(|item| item.get(key))
+            .and_then(|v| v.as_n().ok())
+            .and_then(|v| v.parse::<i64>().ok())
+    };
+    let device_stats = DeviceStats {
+        device_id: board_uuid.to_string(),
+        rejected_readings: number("rejected_readings").unwrap_or(0),
+        last_rejected: number("last_rejected"),
+    };
 
-    match resp {
-        Ok(_e) => {
-            let response_body = ResponseBody {
-                error: false,
-                message: "Device added successfully",
-            };
+    let response = Response::builder()
+        .status(200)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&device_stats).unwrap().into())
+        .map_err(Box::new)?;
+    Ok(response)
+}
+
+pub async fn add_devices(req: Request, client: &Client) -> Result<Response<Body>, Error> {
+    let user_id = get_user_id(&req);
+
+    let board_uuid = req
+        .query_string_parameters_ref()
+        .and_then(|params| params.first("device_id"))
+        .unwrap();
 
-            let response = Response::builder()
-                .status(200)
-                .header("content-type", "text/html")
-                .body(serde_json::to_string(&response_body).unwrap().into())
-                .map_err(Box::new)?;
+    // IANA name (e.g. Europe/Rome), history days and months are computed in this timezone
+    let timezone = req
+        .query_string_parameters_ref()
+        .and_then(|params| params.first("timezone"));
 
-            Ok(response)
+    if let Some(timezone) = timezone {
+        if timezone.parse::<Tz>().is_err() {
+            return message_response(400, true, "Invalid timezone");
         }
-        Err(error) => Err(error.into()),
+    }
+
+    let claim_code = match req
+        .query_string_parameters_ref()
+        .and_then(|params| params.first("claim_code"))
+    {
+        Some(claim_code) => claim_code,
+        None => return message_response(400, true, "Missing claim code"),
+    };
+
+    match claim_device(client, &user_id, board_uuid, claim_code, timezone).await? {
+        ClaimResult::Claimed => message_response(200, false, "Device added successfully"),
+        ClaimResult::AlreadyOwned => message_response(200, false, "Device already added"),
+        ClaimResult::NotFound => message_response(404, true, "Device not found"),
+        ClaimResult::InvalidCode => message_response(403, true, "Invalid claim code"),
+        ClaimResult::Conflict => message_response(409, true, "Device already claimed by another user"),
+        ClaimResult::TooManyAttempts => message_response(429, true, "Too many invalid claim codes, try again later"),
     }
 }
 
@@ -101,33 +221,300 @@ pub async fn delete_devices(req: Request, client: &Client) -> Result<Response<Bo
         .and_then(|params| params.first("device_id"))
         .unwrap(); 
     
-   let request = client
-        .delete_item()
+    let cascade = req
+        .query_string_parameters_ref()
+        .and_then(|params| params.first("cascade"))
+        == Some("true");
+
+    let device = client
+        .get_item()
         .table_name(DEVICE_TABLE_NAME)
-        .key("user_id", AttributeValue::S(user_id.into()))
-        .key("device_id", AttributeValue::S(board_uuid.into()))
+        .key("user_id", AttributeValue::S(user_id.clone()))
+        .key("device_id", AttributeValue::S(board_uuid.to_string()))
         .send()
-        .await;
-    
-        match request {
-        Ok(_out) => {
-             let response_body = ResponseBody {
-                error: false,
-                message: "Device deleted successfully",
-            };
-
-            let response = Response::builder()
-                .status(200)
-                .header("content-type", "text/html")
-                .body(serde_json::to_string(&response_body).unwrap().into())
-                .map_err(Box::new)?;
-
-            Ok(response)
+        .await?;
+    if device.item().is_none() {   // Only the owner can delete the device
+        return message_response(404, true, "Device not found");
+    }
+
+    // Grants go first, a failure can't leave shared users on a device the owner already released
+    delete_grants(client, board_uuid).await?;
+
+    if !cascade {
+        if !release_device(client, &user_id, board_uuid).await? {
+            return message_response(404, true, "Device not found");
         }
-        Err(e) => {
-            Err(e.into())
-        },
+        return message_response(200, false, "Device deleted successfully");
+    }
+
+    // Deleting all the readings takes longer than API Gateway waits, device_cleanup_lambda removes
+    // them in the background and releases the device at the end. Calling DELETE again restarts it
+    if !mark_deleting(client, &user_id, board_uuid).await? {
+        return message_response(404, true, "Device not found");
+    }
+    start_cleanup(&user_id, board_uuid).await?;
+    message_response(202, false, "Device deletion started")
+}
+
+pub async fn update_device(req: Request, client: &Client) -> Result<Response<Body>, Error> {
+    let user_id = get_user_id(&req);
+
+    let board_uuid = req
+        .query_string_parameters_ref()
+        .and_then(|params| params.first("device_id"))
+        .unwrap();
+
+    let body = std::str::from_utf8(req.body()).unwrap_or_default();
+    let update = match serde_json::from_str::<DeviceUpdate>(body) {
+        Ok(update) if !update.is_empty() => update,
+        _ => return message_response(400, true, "Nothing to update"),
+    };
+    // Provisioning, active and offline are set by the backend when readings arrive
+    if let Some(status) = update.status {
+        if status != DeviceStatus::Retired && status != DeviceStatus::Active {
+            return message_response(400, true, "Status can only be retired or active");
+        }
+    }
+    if let Some(timezone) = &update.timezone {
+        if timezone.parse::<Tz>().is_err() {
+            return message_response(400, true, "Invalid timezone");
+        }
+    }
+
+    let access = match get_access(client, &user_id, board_uuid).await? {
+        Some(access) => access,
+        None => return message_response(404, true, "Device not found"),
+    };
+    if !access.role.can_edit() {
+        return message_response(403, true, "Viewers cannot edit the device");
+    }
+
+    let mut set = Vec::new();
+    let mut request = client
+        .update_item()
+        .table_name(DEVICE_TABLE_NAME)
+        .key("user_id", AttributeValue::S(access.owner_id))   // Shared users edit the row of the owner
+        .key("device_id", AttributeValue::S(board_uuid.to_string()))
+        // A device being deleted can't be brought back
+        .condition_expression("attribute_exists(device_id) AND (attribute_not_exists(#current_status) OR #current_status <> :deleting)")
+        .expression_attribute_names("#current_status", "status")
+        .expression_attribute_values(":deleting", AttributeValue::S(DeviceStatus::Deleting.as_str().to_string()));
+
+    let fields = [
+        ("name", update.name),
+        ("location", update.location),
+        ("status", update.status.map(|v| v.as_str().to_string())),
+        ("timezone", update.timezone),
+    ];
+    for (key, value) in fields {
+        if let Some(value) = value {
+            set.push(format!("#{0} = :{0}", key));
+            request = request
+                .expression_attribute_names(format!("#{}", key), key)   // name, location, status and timezone are reserved words
+                .expression_attribute_values(format!(":{}", key), AttributeValue::S(value));
+        }
+    }
+
+    let result = request.update_expression(format!("SET {}", set.join(", "))).send().await;
+    match result {
+        Ok(_out) => message_response(200, false, "Device updated successfully"),
+        Err(err) => {
+            if err.as_service_error().map(|e| e.is_conditional_check_failed_exception()) == Some(true) {
+                message_response(404, true, "Device not found")
+            } else {
+                Err(err.into())
+            }
+        }
+    }
+}
+
+fn query_param<'a>(req: &'a Request, name: &str) -> Option<&'a str> {
+    req.query_string_parameters_ref().and_then(|params| params.first(name))
+}
+
+fn json_response<T: Serialize>(body: &T) -> Result<Response<Body>, Error> {
+    let response = Response::builder()
+        .status(200)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(body).unwrap().into())
+        .map_err(Box::new)?;
+    Ok(response)
+}
+
+/// Invite another user to the device, or change the role of a user already invited
+pub async fn share_device(req: Request, client: &Client) -> Result<Response<Body>, Error> {
+    let user_id = get_user_id(&req);
+    let board_uuid = query_param(&req, "device_id").unwrap();
+    let grantee = query_param(&req, "grantee").unwrap();
+
+    let role = match query_param(&req, "role").and_then(Role::parse) {
+        Some(role) if role.can_be_granted() => role,
+        _ => return message_response(400, true, "Invalid role, use viewer or editor"),
+    };
+    let access = match get_access(client, &user_id, board_uuid).await? {
+        Some(access) => access,
+        None => return message_response(404, true, "Device not found"),
+    };
+    if !access.role.can_share() || access.owner_id != user_id {
+        return message_response(403, true, "Only owners can share the device");
+    }
+    if grantee == access.owner_id {
+        return message_response(400, true, "The device already belongs to this user");
+    }
+
+    let grant = Grant {
+        device_id: board_uuid.to_string(),
+        user_id: grantee.to_string(),
+        owner_id: access.owner_id,
+        role,
+        invited_by: Some(user_id),
+        created_at: Some(Utc::now().timestamp()),
+    };
+    put_grant(client, &grant).await?;
+    message_response(200, false, "Device shared successfully")
+}
+
+/// Stop sharing the device with a user, shared users can also remove themselves
+pub async fn revoke_access(req: Request, client: &Client) -> Result<Response<Body>, Error> {
+    let user_id = get_user_id(&req);
+    let board_uuid = query_param(&req, "device_id").unwrap();
+    let grantee = query_param(&req, "grantee").unwrap();
+
+    let access = match get_access(client, &user_id, board_uuid).await? {
+        Some(access) => access,
+        None => return message_response(404, true, "Device not found"),
+    };
+    if grantee != user_id && (!access.role.can_share() || access.owner_id != user_id) {
+        return message_response(403, true, "Only owners can remove other users");
+    }
+
+    if delete_grant(client, board_uuid, grantee).await? {
+        message_response(200, false, "Access removed successfully")
+    } else {
+        message_response(404, true, "Device not shared with this user")
+    }
+}
+
+/// Users the device is shared with, visible to everyone with access
+pub async fn get_device_grants(req: Request, client: &Client) -> Result<Response<Body>, Error> {
+    let user_id = get_user_id(&req);
+    let board_uuid = query_param(&req, "device_id").unwrap();
+
+    if get_access(client, &user_id, board_uuid).await?.is_none() {
+        return message_response(404, true, "Device not found");
+    }
+    json_response(&get_grants(client, board_uuid).await?)
+}
+
+/// Devices other users shared with the user
+pub async fn get_shared_devices(req: Request, client: &Client) -> Result<Response<Body>, Error> {
+    let user_id = get_user_id(&req);
+    json_response(&get_shared_with(client, &user_id).await?)
+}
+
+/// Send a command to the device, the app polls the command to know when the device executed it
+pub async fn send_command(req: Request, client: &Client) -> Result<Response<Body>, Error> {
+    let user_id = get_user_id(&req);
+    let board_uuid = query_param(&req, "device_id").unwrap();
+
+    let body = std::str::from_utf8(req.body()).unwrap_or_default();
+    let command = match serde_json::from_str::<Command>(body) {
+        Ok(command) if command.is_valid() => command,
+        _ => return message_response(400, true, "Invalid command"),
+    };
+
+    let access = match get_access(client, &user_id, board_uuid).await? {
+        Some(access) => access,
+        None => return message_response(404, true, "Device not found"),
+    };
+    if !access.role.can_edit() {
+        return message_response(403, true, "Viewers cannot send commands");
+    }
+
+    let device = client
+        .get_item()
+        .table_name(DEVICE_TABLE_NAME)
+        .key("user_id", AttributeValue::S(access.owner_id))
+        .key("device_id", AttributeValue::S(board_uuid.to_string()))
+        .send()
+        .await?;
+    if device.item().map(Device::from).map(|device| device.status) == Some(DeviceStatus::Retired) {
+        return message_response(409, true, "Device is retired");
+    }
+
+    let mut record = create_command(client, board_uuid, &user_id, &command).await?;
+    let shared_config = aws_config::load_from_env().await;
+    let iot_data_client = aws_sdk_iotdataplane::Client::new(&shared_config);
+    if publish_command(&iot_data_client, &record, &command).await {
+        set_status(client, &mut record, CommandStatus::Sent).await?;
+    } else {
+        set_status(client, &mut record, CommandStatus::Failed).await?;
+    }
+    json_response(&record)
+}
+
+/// Status of a command, or the command history of the device with command_id missing
+pub async fn get_device_commands(req: Request, client: &Client) -> Result<Response<Body>, Error> {
+    let user_id = get_user_id(&req);
+    let board_uuid = query_param(&req, "device_id").unwrap();
+
+    if get_access(client, &user_id, board_uuid).await?.is_none() {   // Viewers can see the history
+        return message_response(404, true, "Device not found");
+    }
+
+    if let Some(command_id) = query_param(&req, "command_id") {
+        return match get_command(client, board_uuid, command_id).await? {
+            Some(record) => json_response(&record),
+            None => message_response(404, true, "Command not found"),
+        };
+    }
+
+    let limit = query_param(&req, "limit")
+        .and_then(|v| v.parse::<i32>().ok())
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let (items, next) = get_commands(client, board_uuid, limit, query_param(&req, "next")).await?;
+    json_response(&CommandPage { items, next })
+}
+
+/// Set or rotate the secret used by the device on device_config_api, admins only.
+/// Devices registered before the secrets were added get their first one here
+pub async fn put_device_secret(req: Request, client: &Client) -> Result<Response<Body>, Error> {
+    if !is_admin(&get_user_id(&req)) {
+        return message_response(403, true, "Admins only");
+    }
+    let board_uuid = match query_param(&req, "device_id") {
+        Some(board_uuid) => board_uuid,
+        None => return message_response(400, true, "Missing device id"),
+    };
+
+    let body = std::str::from_utf8(req.body()).unwrap_or_default();
+    let request = if body.trim().is_empty() {
+        SecretRequest::default()
+    } else {
+        match serde_json::from_str::<SecretRequest>(body) {
+            Ok(request) => request,
+            Err(_) => return message_response(400, true, "Invalid body"),
+        }
+    };
+    let device_secret = match request.device_secret {
+        Some(secret) if secret.len() < MIN_SECRET_LENGTH => {
+            return message_response(400, true, "Device secret too short");
+        }
+        Some(secret) => secret,
+        None => {
+            let bytes: [u8; 16] = rand::thread_rng().gen();
+            bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+        }
+    };
+
+    if !set_device_secret(client, board_uuid, &device_secret).await? {
+        return message_response(404, true, "Device not found");
     }
+    json_response(&SecretResponse {
+        device_id: board_uuid.to_string(),
+        device_secret,
+    })
 }
 
 pub fn not_implemented() -> Result<Response<Body>, Error> {
@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::Client;
+use helper::sharing::{Access, Role, DEVICE_GRANTS_TABLE, USER_GRANTS_INDEX};
+use lambda_http::Error;
+use serde::Serialize;
+use tokio_stream::StreamExt;
+
+const DEVICE_TABLE_NAME: &str = "devices";
+
+/// A user the device is shared with
+#[derive(Serialize, Clone, Debug)]
+pub struct Grant {
+    pub device_id: String,
+    pub user_id: String,
+    pub owner_id: String,
+    pub role: Role,
+    pub invited_by: Option<String>,
+    pub created_at: Option<i64>,
+}
+
+impl From<&HashMap<String, AttributeValue>> for Grant {
+    fn from(item: &HashMap<String, AttributeValue>) -> Self {
+        let string = |key: &str| item.get(key).and_then(|v| v.as_s().ok()).map(|v| v.to_string());
+        Grant {
+            device_id: string("device_id").unwrap_or_default(),
+            user_id: string("user_id").unwrap_or_default(),
+            owner_id: string("owner_id").unwrap_or_default(),
+            role: string("role").and_then(|v| Role::parse(&v)).unwrap_or(Role::Viewer),
+            invited_by: string("invited_by"),
+            created_at: item
+                .get("created_at")
+                .and_then(|v| v.as_n().ok())
+                .and_then(|v| v.parse::<i64>().ok()),
+        }
+    }
+}
+
+/// Role of the user on the device, None if the device is neither owned by nor shared with the user
+pub async fn get_access(client: &Client, user_id: &str, device_id: &str) -> Result<Option<Access>, Error> {
+    let device = client
+        .get_item()
+        .table_name(DEVICE_TABLE_NAME)
+        .key("user_id", AttributeValue::S(user_id.to_string()))
+        .key("device_id", AttributeValue::S(device_id.to_string()))
+        .send()
+        .await?;
+    if device.item().is_some() {
+        return Ok(Some(Access { role: Role::Owner, owner_id: user_id.to_string() }));
+    }
+
+    let grant = client
+        .get_item()
+        .table_name(DEVICE_GRANTS_TABLE)
+        .key("device_id", AttributeValue::S(device_id.to_string()))
+        .key("user_id", AttributeValue::S(user_id.to_string()))
+        .send()
+        .await?;
+    Ok(grant.item().map(|item| {
+        let grant = Grant::from(item);
+        // Grants saved as owner before only viewer and editor were accepted can still edit, not share
+        let role = if grant.role.can_be_granted() { grant.role } else { Role::Editor };
+        Access { role, owner_id: grant.owner_id }
+    }))
+}
+
+pub async fn put_grant(client: &Client, grant: &Grant) -> Result<(), Error> {
+    let mut request = client
+        .put_item()
+        .table_name(DEVICE_GRANTS_TABLE)
+        .item("device_id", AttributeValue::S(grant.device_id.clone()))
+        .item("user_id", AttributeValue::S(grant.user_id.clone()))
+        .item("owner_id", AttributeValue::S(grant.owner_id.clone()))
+        .item("role", AttributeValue::S(grant.role.as_str().to_string()));
+    if let Some(invited_by) = &grant.invited_by {
+        request = request.item("invited_by", AttributeValue::S(invited_by.clone()));
+    }
+    if let Some(created_at) = grant.created_at {
+        request = request.item("created_at", AttributeValue::N(created_at.to_string()));
+    }
+    request.send().await?;
+    Ok(())
+}
+
+/// Remove a grant, false if the device was not shared with the user
+pub async fn delete_grant(client: &Client, device_id: &str, user_id: &str) -> Result<bool, Error> {
+    let result = client
+        .delete_item()
+        .table_name(DEVICE_GRANTS_TABLE)
+        .key("device_id", AttributeValue::S(device_id.to_string()))
+        .key("user_id", AttributeValue::S(user_id.to_string()))
+        .return_values(aws_sdk_dynamodb::types::ReturnValue::AllOld)
+        .send()
+        .await?;
+    Ok(result.attributes().is_some())
+}
+
+/// Every user the device is shared with
+pub async fn get_grants(client: &Client, device_id: &str) -> Result<Vec<Grant>, Error> {
+    let items: Result<Vec<_>, _> = client
+        .query()
+        .table_name(DEVICE_GRANTS_TABLE)
+        .key_condition_expression("#device_id = :device_id")
+        .expression_attribute_names("#device_id", "device_id")
+        .expression_attribute_values(":device_id", AttributeValue::S(device_id.to_string()))
+        .into_paginator()
+        .items()
+        .send()
+        .collect()
+        .await;
+    Ok(items?.iter().map(Grant::from).collect())
+}
+
+/// Every device shared with the user
+pub async fn get_shared_with(client: &Client, user_id: &str) -> Result<Vec<Grant>, Error> {
+    let items: Result<Vec<_>, _> = client
+        .query()
+        .table_name(DEVICE_GRANTS_TABLE)
+        .index_name(USER_GRANTS_INDEX)
+        .key_condition_expression("#user_id = :user_id")
+        .expression_attribute_names("#user_id", "user_id")
+        .expression_attribute_values(":user_id", AttributeValue::S(user_id.to_string()))
+        .into_paginator()
+        .items()
+        .send()
+        .collect()
+        .await;
+    Ok(items?.iter().map(Grant::from).collect())
+}
+
+/// Remove every grant of the device, when it is released the new owner starts without shares
+pub async fn delete_grants(client: &Client, device_id: &str) -> Result<usize, Error> {
+    let grants = get_grants(client, device_id).await?;
+    for grant in grants.iter() {
+        delete_grant(client, device_id, &grant.user_id).await?;
+    }
+    Ok(grants.len())
+}
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use aws_sdk_dynamodb::types::{AttributeValue, DeleteRequest, WriteRequest};
+use aws_sdk_dynamodb::Client;
+use aws_sdk_iotdataplane::primitives::Blob;
+use helper::command::DEVICE_COMMANDS_TABLE;
+use helper::sharing::DEVICE_GRANTS_TABLE;
+use lambda_runtime::Error;
+use serde::Serialize;
+use tokio_stream::StreamExt;
+
+const PLANTS_TABLE: &str = "plants";                        // Written by plant_info_api
+const TELEMETRY_TABLE: &str = "sensor_telemetry";           // Written by mqtt_month_media_processor
+const QUARANTINE_TABLE: &str = "sensor_quarantine";
+const ROLLUP_TABLE: &str = "sensor_rollups";                // Written by create_history_lambda
+const AGGREGATE_TABLE: &str = "sensor_aggregates";          // Written by stream_aggregation_lambda
+const DEVICE_STATS_TABLE: &str = "device_stats";
+const ALERT_STATE_TABLE: &str = "notification_alert_state"; // Written by notification_sender
+const BATCH_SIZE: usize = 25;   // BatchWriteItem limit
+const DELETE_RETRIES: u32 = 5;
+
+/// What was removed with the device, logged by the lambda and saved in the inbox of the owner
+#[derive(Serialize, Default, Debug)]
+pub struct CleanupSummary {
+    pub plants: usize,
+    pub telemetry: usize,
+    pub quarantine: usize,
+    pub rollups: usize,
+    pub aggregates: usize,
+    pub alert_states: usize,
+    pub device_stats: usize,
+    pub commands: usize,
+    pub grants: usize,      // Users the device was shared with
+    pub mqtt_config_cleared: bool,
+}
+
+/// Keys of every row of the partition, an empty list if the table was never created
+async fn get_keys(
+    client: &Client,
+    table_name: &str,
+    key_condition: &str,
+    values: Vec<(&str, AttributeValue)>,
+    keys: (&str, &str),
+) -> Result<Vec<HashMap<String, AttributeValue>>, Error> {
+    let mut request = client
+        .query()
+        .table_name(table_name)
+        .key_condition_expression(key_condition)
+        .projection_expression("#hash, #range")
+        .expression_attribute_names("#hash", keys.0)
+        .expression_attribute_names("#range", keys.1);
+    for (name, value) in values {
+        request = request.expression_attribute_values(name, value);
+    }
+
+    let items: Result<Vec<_>, _> = request.into_paginator().items().send().collect().await;
+    match items {
+        Ok(items) => Ok(items),
+        Err(err) => {
+            if err.as_service_error().map(|e| e.is_resource_not_found_exception()) == Some(true) {
+                Ok(Vec::new())
+            } else {
+                Err(err.into())
+            }
+        }
+    }
+}
+
+/// Delete the rows in batches, retrying the unprocessed ones
+async fn delete_keys(client: &Client, table_name: &str, keys: Vec<HashMap<String, AttributeValue>>) -> Result<usize, Error> {
+    let count = keys.len();
+    for chunk in keys.chunks(BATCH_SIZE) {
+        let mut requests: Vec<WriteRequest> = chunk
+            .iter()
+            .map(|key| {
+                WriteRequest::builder()
+                    .delete_request(DeleteRequest::builder().set_key(Some(key.clone())).build())
+                    .build()
+            })
+            .collect();
+
+        for retry in 0..DELETE_RETRIES {
+            let output = client
+                .batch_write_item()
+                .request_items(table_name, requests.clone())
+                .send()
+                .await?;
+            requests = output
+                .unprocessed_items()
+                .and_then(|unprocessed| unprocessed.get(table_name))
+                .cloned()
+                .unwrap_or_default();
+            if requests.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100 * 2u64.pow(retry))).await;
+        }
+        if !requests.is_empty() {
+            return Err(format!("Cannot delete every row of {}", table_name).into());
+        }
+    }
+    Ok(count)
+}
+
+async fn delete_sensor_rows(client: &Client, table_name: &str, range_key: &str, device_id: &str) -> Result<usize, Error> {
+    let keys = get_keys(
+        client,
+        table_name,
+        "#hash = :uuid",
+        vec![(":uuid", AttributeValue::S(device_id.to_string()))],
+        ("uuid", range_key),
+    )
+    .await?;
+    delete_keys(client, table_name, keys).await
+}
+
+/// Remove everything tied to the sensor, device_api already checked the user owns it.
+/// Safe to repeat, a second run just finds nothing to delete
+pub async fn cleanup_device(
+    client: &Client,
+    iot_data_client: &aws_sdk_iotdataplane::Client,
+    user_id: &str,
+    device_id: &str,
+) -> Result<CleanupSummary, Error> {
+    let mut summary = CleanupSummary::default();
+
+    let plant = client
+        .delete_item()
+        .table_name(PLANTS_TABLE)
+        .key("user_id", AttributeValue::S(user_id.to_string()))
+        .key("sensor_id", AttributeValue::S(device_id.to_string()))
+        .return_values(aws_sdk_dynamodb::types::ReturnValue::AllOld)
+        .send()
+        .await?;
+    summary.plants = plant.attributes().map(|_| 1).unwrap_or(0);
+
+    // An empty retained message removes the plant config kept by the broker for the ESP8266
+    let cleared = iot_data_client
+        .publish()
+        .topic(format!("sensor/plants/{}", device_id))
+        .retain(true)
+        .payload(Blob::new(Vec::new()))
+        .send()
+        .await;
+    match cleared {
+        Ok(_out) => summary.mqtt_config_cleared = true,
+        Err(err) => println!("{:?}", err),
+    }
+
+    summary.telemetry = delete_sensor_rows(client, TELEMETRY_TABLE, "timestamp", device_id).await?;
+    summary.quarantine = delete_sensor_rows(client, QUARANTINE_TABLE, "timestamp", device_id).await?;
+    summary.rollups = delete_sensor_rows(client, ROLLUP_TABLE, "window_key", device_id).await?;
+    summary.aggregates = delete_sensor_rows(client, AGGREGATE_TABLE, "window_key", device_id).await?;
+
+    let alert_states = get_keys(
+        client,
+        ALERT_STATE_TABLE,
+        "#hash = :user_id AND begins_with(#range, :prefix)",
+        vec![
+            (":user_id", AttributeValue::S(user_id.to_string())),
+            (":prefix", AttributeValue::S(format!("{}#", device_id))),
+        ],
+        ("user_id", "alert_key"),
+    )
+    .await?;
+    summary.alert_states = delete_keys(client, ALERT_STATE_TABLE, alert_states).await?;
+
+    let stats = client
+        .delete_item()
+        .table_name(DEVICE_STATS_TABLE)
+        .key("device_id", AttributeValue::S(device_id.to_string()))
+        .return_values(aws_sdk_dynamodb::types::ReturnValue::AllOld)
+        .send()
+        .await?;
+    summary.device_stats = stats.attributes().map(|_| 1).unwrap_or(0);
+
+    let commands = get_keys(
+        client,
+        DEVICE_COMMANDS_TABLE,
+        "#hash = :device_id",
+        vec![(":device_id", AttributeValue::S(device_id.to_string()))],
+        ("device_id", "command_id"),
+    )
+    .await?;
+    summary.commands = delete_keys(client, DEVICE_COMMANDS_TABLE, commands).await?;
+
+    // device_api already removed them, a grant may have been added before the device was marked as deleting
+    let grants = get_keys(
+        client,
+        DEVICE_GRANTS_TABLE,
+        "#hash = :device_id",
+        vec![(":device_id", AttributeValue::S(device_id.to_string()))],
+        ("device_id", "user_id"),
+    )
+    .await?;
+    summary.grants = delete_keys(client, DEVICE_GRANTS_TABLE, grants).await?;
+
+    Ok(summary)
+}
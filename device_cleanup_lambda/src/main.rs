@@ -0,0 +1,167 @@
+use aws_config::load_from_env;
+use aws_sdk_dynamodb::types::{AttributeValue, Delete, TransactWriteItem, Update};
+use aws_sdk_dynamodb::Client;
+use helper::claim::DEVICE_REGISTRY_TABLE;
+use chrono::Utc;
+use lambda_runtime::{run, service_fn, Error, LambdaEvent};
+use serde::{Deserialize, Serialize};
+mod cleanup;
+use cleanup::{cleanup_device, CleanupSummary};
+
+const DEVICE_TABLE_NAME: &str = "devices";
+const DELETING_STATUS: &str = "deleting";   // Set by device_api before invoking this lambda
+const HISTORY_TABLE: &str = "notification_history"; // Read by notification_api for the in-app inbox
+
+/*
+    Invoked asynchronously by device_api on DELETE with cascade=true, after the device
+    was marked as deleting and its grants were removed:
+
+    {
+        "user_id": "WLk7Giku6TYBMI22wfmTSJbWOVA2",
+        "device_id": "297a0620-3b4d-40ed-b407-2216eb0d"
+    }
+
+    The device is released only after its data is gone. If the cleanup fails the invocation
+    is retried by Lambda, and the owner can call DELETE again. The invocation is asynchronous,
+    so the summary of what was removed is logged and saved in the inbox of the owner.
+*/
+#[derive(Deserialize)]
+struct Request {
+    user_id: String,
+    device_id: String,
+}
+
+#[derive(Serialize)]
+struct Response {
+    req_id: String,
+    msg: String,
+    removed: Option<CleanupSummary>,
+}
+
+/// True if the user still owns the device and asked to delete it
+async fn is_deleting(client: &Client, user_id: &str, device_id: &str) -> Result<bool, Error> {
+    let device = client
+        .get_item()
+        .table_name(DEVICE_TABLE_NAME)
+        .key("user_id", AttributeValue::S(user_id.to_string()))
+        .key("device_id", AttributeValue::S(device_id.to_string()))
+        .consistent_read(true)
+        .send()
+        .await?;
+    Ok(device
+        .item()
+        .and_then(|item| item.get("status"))
+        .and_then(|v| v.as_s().ok())
+        .map(|status| status == DELETING_STATUS)
+        .unwrap_or(false))
+}
+
+/// Remove the device from the account of the owner so it can be claimed again
+async fn release_device(client: &Client, user_id: &str, device_id: &str) -> Result<(), Error> {
+    let delete_device = Delete::builder()
+        .table_name(DEVICE_TABLE_NAME)
+        .key("user_id", AttributeValue::S(user_id.to_string()))
+        .key("device_id", AttributeValue::S(device_id.to_string()))
+        .build();
+
+    let registry = client
+        .get_item()
+        .table_name(DEVICE_REGISTRY_TABLE)
+        .key("device_id", AttributeValue::S(device_id.to_string()))
+        .send()
+        .await?;
+
+    let mut request = client
+        .transact_write_items()
+        .transact_items(TransactWriteItem::builder().delete(delete_device).build());
+
+    // Devices added before the registry have only the row in the devices table
+    if registry.item().is_some() {
+        let remove_owner = Update::builder()
+            .table_name(DEVICE_REGISTRY_TABLE)
+            .key("device_id", AttributeValue::S(device_id.to_string()))
+            .update_expression("REMOVE #owner, claimed_at")
+            .condition_expression("#owner = :owner")
+            .expression_attribute_names("#owner", "owner")
+            .expression_attribute_values(":owner", AttributeValue::S(user_id.to_string()))
+            .build();
+        request = request.transact_items(TransactWriteItem::builder().update(remove_owner).build());
+    }
+
+    request.send().await?;
+    Ok(())
+}
+
+/// Tell the owner what was removed, the inbox entry has kind deleted and the summary as payload
+async fn save_summary(client: &Client, user_id: &str, device_id: &str, summary: &CleanupSummary) {
+    let now = Utc::now();
+    let body = format!(
+        "Il sensore è stato eliminato con {} letture, {} riepiloghi, {} piante e {} condivisioni",
+        summary.telemetry,
+        summary.rollups + summary.aggregates,
+        summary.plants,
+        summary.grants
+    );
+    let result = client
+        .put_item()
+        .table_name(HISTORY_TABLE)
+        .item("user_id", AttributeValue::S(user_id.to_string()))
+        .item("notification_id", AttributeValue::S(format!("{:013}#{}#deleted#", now.timestamp_millis(), device_id)))
+        .item("kind", AttributeValue::S("deleted".to_string()))
+        .item("alert_type", AttributeValue::S("".to_string()))
+        .item("sensor_id", AttributeValue::S(device_id.to_string()))
+        .item("title", AttributeValue::S("Agromate sensore eliminato".to_string()))
+        .item("body", AttributeValue::S(body))
+        .item("payload", AttributeValue::S(serde_json::to_string(summary).unwrap()))
+        .item("delivered", AttributeValue::N("0".to_string()))
+        .item("failed", AttributeValue::N("0".to_string()))
+        .item("read", AttributeValue::Bool(false))
+        .item("timestamp", AttributeValue::N(now.timestamp().to_string()))
+        .send()
+        .await;
+
+    if let Err(err) = result {
+        println!("{:?}", err); // The device is already deleted, losing the inbox entry must not retry the cleanup
+    }
+}
+
+async fn function_handler(event: LambdaEvent<Request>) -> Result<Response, Error> {
+    let shared_config = load_from_env().await;
+    let client = Client::new(&shared_config);
+    let iot_data_client = aws_sdk_iotdataplane::Client::new(&shared_config);
+    let Request { user_id, device_id } = &event.payload;
+
+    // A repeated invocation after the release finds nothing to do
+    if !is_deleting(&client, user_id, device_id).await? {
+        return Ok(Response {
+            req_id: event.context.request_id,
+            msg: format!("{} is not being deleted.", device_id),
+            removed: None,
+        });
+    }
+
+    let summary = cleanup_device(&client, &iot_data_client, user_id, device_id).await?;
+    release_device(&client, user_id, device_id).await?;
+    println!("{} of {} deleted: {:?}", device_id, user_id, summary);
+    save_summary(&client, user_id, device_id, &summary).await;
+
+    let resp = Response {
+        req_id: event.context.request_id,
+        msg: format!("{} and its data deleted.", device_id),
+        removed: Some(summary),
+    };
+    Ok(resp)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::ERROR)
+        // disable printing the name of the module in every log line.
+        .with_target(false)
+        // disabling time is handy because CloudWatch will add the ingestion time.
+        .without_time()
+        .init();
+
+    run(service_fn(function_handler)).await
+}
@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use serde::Serialize;
+
+/*
+    Plant config for the ESP8266, short keys keep the response small:
+
+    {
+        "t": 22.0,      default_temperature
+        "tl": 5.0,      temperature_limit
+        "h": 60.0,      default_humidity
+        "hl": 10.0,     humidity_limit
+        "p": 40.0,      default_precipitation
+        "pl": 10.0,     precipitation_limit
+        "lc": "#FF00FF",    default_light_color
+        "lt": 12.0,     light_time
+        "li": 80        light_intensity
+    }
+
+    Notification flags are not sent, alerts are checked by notification_sender.
+*/
+#[derive(Serialize, Debug, PartialEq)]
+pub struct PlantConfig {
+    pub t: f32,
+    pub tl: f32,
+    pub h: f32,
+    pub hl: f32,
+    pub p: f32,
+    pub pl: f32,
+    pub lc: String,
+    pub lt: f32,
+    pub li: i32,
+}
+
+fn attribute_to_f32(item: &HashMap<String, AttributeValue>, key: &str) -> f32 {
+    item.get(key)
+        .and_then(|v| v.as_n().ok())
+        .and_then(|v| v.parse::<f32>().ok())
+        .unwrap_or_default()
+}
+
+impl From<&HashMap<String, AttributeValue>> for PlantConfig {
+    fn from(item: &HashMap<String, AttributeValue>) -> Self {
+        // plant_info_api saves the intensity as light_intensiy
+        let li = ["light_intensity", "light_intensiy"]
+            .iter()
+            .find_map(|key| item.get(*key).and_then(|v| v.as_n().ok()))
+            .and_then(|v| v.parse::<i32>().ok())
+            .unwrap_or_default();
+        PlantConfig {
+            t: attribute_to_f32(item, "default_temperature"),
+            tl: attribute_to_f32(item, "temperature_limit"),
+            h: attribute_to_f32(item, "default_humidity"),
+            hl: attribute_to_f32(item, "humidity_limit"),
+            p: attribute_to_f32(item, "default_precipitation"),
+            pl: attribute_to_f32(item, "precipitation_limit"),
+            lc: item
+                .get("default_light_color")
+                .and_then(|v| v.as_s().ok())
+                .cloned()
+                .unwrap_or_default(),
+            lt: attribute_to_f32(item, "light_time"),
+            li,
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use aws_config::load_from_env;
+use aws_sdk_dynamodb::{types::AttributeValue, Client};
+use helper::claim::DEVICE_REGISTRY_TABLE;
+use helper::device_auth::{verify_device_secret, DEVICE_ID_HEADER, DEVICE_SECRET_HEADER, MAX_FAILED_CREDENTIALS, SECRET_HASH_ATTRIBUTE};
+use helper::retention::TTL_ATTRIBUTE;
+use helper::throttle::{attempt_expires_at, attempt_key, FAILED_ATTEMPTS_TABLE};
+use chrono::Utc;
+use lambda_http::request::RequestContext;
+use lambda_http::{run, service_fn, Body, Error, Request, RequestExt, Response};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+mod config;
+use config::PlantConfig;
+
+const PLANTS_TABLE: &str = "plants";    // Written by plant_info_api
+const DEVICE_TABLE: &str = "devices";   // status is set by device_api
+
+#[derive(Serialize)]
+struct ResponseBody<'a> {
+    error: bool,
+    message: &'a str,
+}
+
+fn message_response(status: u16, message: &str) -> Result<Response<Body>, Error> {
+    let response_body = ResponseBody { error: true, message };
+
+    let response = Response::builder()
+        .status(status)
+        .header("content-type", "text/html")
+        .body(serde_json::to_string(&response_body).unwrap().into())
+        .map_err(Box::new)?;
+    Ok(response)
+}
+
+enum Auth {
+    Owner(String),
+    NotClaimed,     // Valid device, no user added it yet
+    Invalid,
+    Throttled,      // Too many invalid credentials for the device from this address, wait for the next window
+}
+
+fn header<'a>(req: &'a Request, name: &str) -> Option<&'a str> {
+    req.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+/// Address the request comes from, as seen by the HTTP API
+fn source_ip(req: &Request) -> String {
+    if let Some(RequestContext::ApiGatewayV2(context)) = req.request_context_ref() {
+        if let Some(source_ip) = &context.http.source_ip {
+            return source_ip.clone();
+        }
+    }
+    "unknown".to_string()
+}
+
+/// Invalid credentials of the device in the current window
+async fn get_failed_credentials(client: &Client, key: &str) -> Result<i64, Error> {
+    let result = client
+        .get_item()
+        .table_name(FAILED_ATTEMPTS_TABLE)
+        .key("attempt_key", AttributeValue::S(key.to_string()))
+        .send()
+        .await?;
+    Ok(result
+        .item()
+        .and_then(|item| item.get("attempts"))
+        .and_then(|v| v.as_n().ok())
+        .and_then(|v| v.parse::<i64>().ok())
+        .unwrap_or(0))
+}
+
+async fn count_failed_credentials(client: &Client, key: &str, now: i64) -> Result<(), Error> {
+    client
+        .update_item()
+        .table_name(FAILED_ATTEMPTS_TABLE)
+        .key("attempt_key", AttributeValue::S(key.to_string()))
+        .update_expression(format!("ADD attempts :one SET {} = :expires_at", TTL_ATTRIBUTE))
+        .expression_attribute_values(":one", AttributeValue::N("1".to_string()))
+        .expression_attribute_values(":expires_at", AttributeValue::N(attempt_expires_at(now).to_string()))
+        .send()
+        .await?;
+    Ok(())
+}
+
+/// Owner of the device if the secret matches the one flashed at manufacturing.
+/// Unknown devices and wrong secrets get the same answer, and are counted to stop guessing the secret.
+/// They are counted per device and address, so whoever knows the device id can't lock out the real ESP8266
+async fn authenticate(client: &Client, device_id: &str, secret: &str, source_ip: &str) -> Result<Auth, Error> {
+    let now = Utc::now().timestamp();
+    let key = attempt_key("device_secret", &format!("{}#{}", device_id, source_ip), now);
+    if get_failed_credentials(client, &key).await? >= MAX_FAILED_CREDENTIALS {
+        return Ok(Auth::Throttled);
+    }
+
+    let auth = check_secret(client, device_id, secret).await?;
+    if matches!(auth, Auth::Invalid) {
+        count_failed_credentials(client, &key, now).await?;
+    }
+    Ok(auth)
+}
+
+async fn check_secret(client: &Client, device_id: &str, secret: &str) -> Result<Auth, Error> {
+    let registry = client
+        .get_item()
+        .table_name(DEVICE_REGISTRY_TABLE)
+        .key("device_id", AttributeValue::S(device_id.to_string()))
+        .send()
+        .await?;
+    let device = match registry.item() {
+        Some(device) => device,
+        None => return Ok(Auth::Invalid),
+    };
+    let secret_hash = device.get(SECRET_HASH_ATTRIBUTE).and_then(|v| v.as_s().ok());
+    match secret_hash {
+        Some(secret_hash) if verify_device_secret(secret, secret_hash) => {
+            match device.get("owner").and_then(|v| v.as_s().ok()) {
+                Some(owner) => Ok(Auth::Owner(owner.to_string())),
+                None => Ok(Auth::NotClaimed),
+            }
+        }
+        _ => Ok(Auth::Invalid),
+    }
+}
+
+/// Plant config of the device, called by the ESP8266 at boot.
+/// The route has no Firebase authorizer, the device sends its id and secret in the headers
+async fn get_config(req: Request, client: &Client) -> Result<Response<Body>, Error> {
+    let (device_id, secret) = match (header(&req, DEVICE_ID_HEADER), header(&req, DEVICE_SECRET_HEADER)) {
+        (Some(device_id), Some(secret)) => (device_id, secret),
+        _ => return message_response(401, "Missing device credentials"),
+    };
+
+    let owner = match authenticate(client, device_id, secret, &source_ip(&req)).await? {
+        Auth::Owner(owner) => owner,
+        Auth::NotClaimed => return message_response(404, "Device not claimed"),
+        Auth::Invalid => return message_response(401, "Invalid device credentials"),
+        Auth::Throttled => return message_response(429, "Too many invalid credentials, try again later"),
+    };
+
+    // Retired devices and devices being deleted get no config
+    let device = client
+        .get_item()
+        .table_name(DEVICE_TABLE)
+        .key("user_id", AttributeValue::S(owner.clone()))
+        .key("device_id", AttributeValue::S(device_id.to_string()))
+        .send()
+        .await?;
+    let status = device.item().and_then(|item| item.get("status")).and_then(|v| v.as_s().ok());
+    if matches!(status.map(|v| v.as_str()), Some("retired") | Some("deleting")) {
+        return message_response(403, "Device retired");
+    }
+
+    let plant = client
+        .get_item()
+        .table_name(PLANTS_TABLE)
+        .key("user_id", AttributeValue::S(owner))
+        .key("sensor_id", AttributeValue::S(device_id.to_string()))
+        .send()
+        .await?;
+    let config = match plant.item() {
+        Some(item) => PlantConfig::from(item),
+        None => return message_response(404, "No plant assigned"),
+    };
+
+    // The ESP8266 sends back the ETag, nothing is downloaded until the plant changes
+    let body = serde_json::to_string(&config).unwrap();
+    let digest = Sha256::digest(body.as_bytes());
+    let etag = format!("\"{}\"", digest.iter().take(8).map(|byte| format!("{:02x}", byte)).collect::<String>());
+    if header(&req, "if-none-match") == Some(etag.as_str()) {
+        let response = Response::builder()
+            .status(304)
+            .header("etag", etag)
+            .body(Body::Empty)
+            .map_err(Box::new)?;
+        return Ok(response);
+    }
+
+    let response = Response::builder()
+        .status(200)
+        .header("content-type", "application/json")
+        .header("etag", etag)
+        .body(body.into())
+        .map_err(Box::new)?;
+    Ok(response)
+}
+
+async fn function_handler(event: Request) -> Result<Response<Body>, Error> {
+    let shared_config = load_from_env().await;
+    let client = Client::new(&shared_config);
+    match event.method().as_str() {
+        "GET" => get_config(event, &client).await,
+        _ => message_response(404, "API not implemented"),
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        // disable printing the name of the module in every log line.
+        .with_target(false)
+        // disabling time is handy because CloudWatch will add the ingestion time.
+        .without_time()
+        .init();
+
+    run(service_fn(function_handler)).await
+}
@@ -0,0 +1,302 @@
+use aws_config::load_from_env;
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::Client;
+use helper::claim::{hash_claim_code, normalize_claim_code, CLAIM_CODE_HMAC_ATTRIBUTE, DEVICE_REGISTRY_TABLE};
+use helper::device_auth::{hash_device_secret, MIN_SECRET_LENGTH, SECRET_HASH_ATTRIBUTE};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/*
+    Add a batch of ESP8266s coming from manufacturing to the device registry.
+
+    cargo run --release -- --dry-run devices.csv     # Check the file without writing
+    cargo run --release -- devices.csv               # Import the devices
+    cargo run --release -- devices.json
+
+    CSV files start with a header, hardware_revision and device_secret are optional.
+    Fields containing commas or quotes must be quoted, with quotes doubled ("rev ""b""").
+    device_secret is flashed on the ESP8266 to authenticate on device_config_api, it needs at least
+    MIN_SECRET_LENGTH characters and can also be set later with PUT of device_api:
+
+    device_id,claim_code,hardware_model,hardware_revision,device_secret
+    297a0620-3b4d-40ed-b407-2216eb0d,K7Q2-9XPA,ESP8266,rev-b,4f1c9a7e2b8d6035
+
+    JSON files are an array of objects with the same fields, see utils/.
+    Claim codes need at least MIN_CLAIM_CODE_LENGTH letters and digits and MIN_CLAIM_CODE_BITS of entropy.
+    Only the hashes of claim codes and secrets are saved, claim codes are hashed with the CLAIM_CODE_KEY
+    environment variable, the same key of device_api. Devices already in the registry are never
+    overwritten and are not errors, so the tool can be run again on the same file after a failure.
+*/
+
+const MIN_CLAIM_CODE_LENGTH: usize = 8;     // Letters and digits, dashes are only for readability
+const MIN_CLAIM_CODE_BITS: f64 = 24.0;      // Entropy of the characters of the code times its length
+
+#[derive(Deserialize, Debug, Clone)]
+struct Row {
+    device_id: String,
+    claim_code: String,
+    hardware_model: String,
+    hardware_revision: Option<String>,
+    device_secret: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+enum RowResult {
+    Imported,
+    Valid,                      // Would be imported, --dry-run
+    Invalid(String),
+    DuplicateInFile(String),    // Position of the first row with the same device_id
+    AlreadyRegistered,          // Imported by a previous run, not an error
+}
+
+impl RowResult {
+    fn is_error(&self) -> bool {
+        matches!(self, RowResult::Invalid(_) | RowResult::DuplicateInFile(_))
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            RowResult::Imported => "imported".to_string(),
+            RowResult::Valid => "valid".to_string(),
+            RowResult::Invalid(reason) => format!("invalid, {}", reason),
+            RowResult::DuplicateInFile(first) => format!("duplicate of {}", first),
+            RowResult::AlreadyRegistered => "already registered".to_string(),
+        }
+    }
+}
+
+/// Rows of the file with their position, or why the row can't be read
+type ParsedRow = (String, Result<Row, String>);
+
+fn parse_csv(content: &str) -> Result<Vec<ParsedRow>, String> {
+    // Fields can be quoted, "rev-b, second batch" is a single hardware_revision
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .comment(Some(b'#'))
+        .from_reader(content.as_bytes());
+
+    let header: Vec<String> = match reader.headers() {
+        Ok(header) if !header.is_empty() => header.iter().map(|v| v.to_lowercase()).collect(),
+        Ok(_) => return Err("Empty file".to_string()),
+        Err(err) => return Err(err.to_string()),
+    };
+    let column = |name: &str| header.iter().position(|v| v == name);
+    let (device_id, claim_code, hardware_model) = match (column("device_id"), column("claim_code"), column("hardware_model")) {
+        (Some(device_id), Some(claim_code), Some(hardware_model)) => (device_id, claim_code, hardware_model),
+        _ => return Err("The header must contain device_id, claim_code and hardware_model".to_string()),
+    };
+    let hardware_revision = column("hardware_revision");
+    let device_secret = column("device_secret");
+
+    Ok(reader
+        .records()
+        .enumerate()
+        .map(|(index, record)| {
+            let position = match &record {
+                Ok(record) => record.position().map(|v| format!("line {}", v.line())),
+                Err(err) => err.position().map(|v| format!("line {}", v.line())),
+            }
+            .unwrap_or_else(|| format!("row {}", index + 1));
+            let row = match record {
+                Err(err) => Err(err.to_string()),
+                Ok(fields) if fields.len() != header.len() => {
+                    Err(format!("expected {} fields, found {}", header.len(), fields.len()))
+                }
+                Ok(fields) => Ok(Row {
+                    device_id: fields[device_id].to_string(),
+                    claim_code: fields[claim_code].to_string(),
+                    hardware_model: fields[hardware_model].to_string(),
+                    hardware_revision: hardware_revision
+                        .map(|column| fields[column].to_string())
+                        .filter(|v| !v.is_empty()),
+                    device_secret: device_secret
+                        .map(|column| fields[column].to_string())
+                        .filter(|v| !v.is_empty()),
+                }),
+            };
+            (position, row)
+        })
+        .collect())
+}
+
+fn parse_json(content: &str) -> Result<Vec<ParsedRow>, String> {
+    // Every entry is decoded on its own, a wrong entry doesn't stop the others
+    let entries: Vec<serde_json::Value> = serde_json::from_str(content).map_err(|err| err.to_string())?;
+    Ok(entries
+        .into_iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            let row = serde_json::from_value::<Row>(entry).map_err(|err| err.to_string());
+            (format!("entry {}", index + 1), row)
+        })
+        .collect())
+}
+
+fn validate(row: &Row) -> Result<(), String> {
+    if row.device_id.is_empty() || !row.device_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err("device_id must contain only letters, digits and -".to_string());
+    }
+    if row.claim_code.trim().is_empty() {
+        return Err("missing claim_code".to_string());
+    }
+    check_claim_code(&row.claim_code)?;
+    if row.hardware_model.trim().is_empty() {
+        return Err("missing hardware_model".to_string());
+    }
+    if let Some(device_secret) = &row.device_secret {
+        if device_secret.len() < MIN_SECRET_LENGTH {
+            return Err(format!("device_secret must have at least {} characters", MIN_SECRET_LENGTH));
+        }
+    }
+    Ok(())
+}
+
+/// Reject codes that can be guessed, like 11111111 or ABABABAB
+fn check_claim_code(claim_code: &str) -> Result<(), String> {
+    // The same string that is hashed, so the checks apply to what the user has to type
+    let code: Vec<char> = normalize_claim_code(claim_code).chars().collect();
+    if !code.iter().all(|c| c.is_ascii_alphanumeric()) {
+        return Err("claim_code must contain only letters, digits and -".to_string());
+    }
+    if code.len() < MIN_CLAIM_CODE_LENGTH {
+        return Err(format!("claim_code must have at least {} letters and digits", MIN_CLAIM_CODE_LENGTH));
+    }
+
+    let mut counts: HashMap<char, usize> = HashMap::new();
+    for c in code.iter() {
+        *counts.entry(*c).or_default() += 1;
+    }
+    let length = code.len() as f64;
+    let bits_per_char: f64 = counts
+        .values()
+        .map(|count| {
+            let p = *count as f64 / length;
+            -p * p.log2()
+        })
+        .sum();
+    if bits_per_char * length < MIN_CLAIM_CODE_BITS {
+        return Err("claim_code is too easy to guess".to_string());
+    }
+    Ok(())
+}
+
+async fn is_registered(client: &Client, device_id: &str) -> Result<bool, aws_sdk_dynamodb::Error> {
+    let result = client
+        .get_item()
+        .table_name(DEVICE_REGISTRY_TABLE)
+        .key("device_id", AttributeValue::S(device_id.to_string()))
+        .send()
+        .await?;
+    Ok(result.item().is_some())
+}
+
+/// Add the device to the registry, false if it is already there.
+/// One PutItem per device, BatchWriteItem can't refuse to overwrite a claimed device
+async fn register(client: &Client, row: &Row, imported_at: u64) -> Result<bool, aws_sdk_dynamodb::Error> {
+    let mut request = client
+        .put_item()
+        .table_name(DEVICE_REGISTRY_TABLE)
+        .item("device_id", AttributeValue::S(row.device_id.clone()))
+        .item(CLAIM_CODE_HMAC_ATTRIBUTE, AttributeValue::S(hash_claim_code(&row.claim_code)))
+        .item("hardware_model", AttributeValue::S(row.hardware_model.trim().to_string()))
+        .item("imported_at", AttributeValue::N(imported_at.to_string()))
+        .condition_expression("attribute_not_exists(device_id)");
+    if let Some(hardware_revision) = &row.hardware_revision {
+        request = request.item("hardware_revision", AttributeValue::S(hardware_revision.clone()));
+    }
+    if let Some(device_secret) = &row.device_secret {
+        request = request.item(SECRET_HASH_ATTRIBUTE, AttributeValue::S(hash_device_secret(device_secret)));
+    }
+
+    match request.send().await {
+        Ok(_out) => Ok(true),
+        Err(err) => {
+            if err.as_service_error().map(|e| e.is_conditional_check_failed_exception()) == Some(true) {
+                Ok(false)
+            } else {
+                Err(err.into())
+            }
+        }
+    }
+}
+
+async fn import_row(
+    client: &Client,
+    row: Result<Row, String>,
+    position: &str,
+    seen: &mut HashMap<String, String>,
+    dry_run: bool,
+    imported_at: u64,
+) -> Result<RowResult, aws_sdk_dynamodb::Error> {
+    let row = match row.and_then(|row| validate(&row).map(|_| row)) {
+        Ok(row) => row,
+        Err(reason) => return Ok(RowResult::Invalid(reason)),
+    };
+    if let Some(first) = seen.get(&row.device_id) {
+        return Ok(RowResult::DuplicateInFile(first.clone()));
+    }
+    seen.insert(row.device_id.clone(), position.to_string());
+
+    if dry_run {
+        if is_registered(client, &row.device_id).await? {
+            return Ok(RowResult::AlreadyRegistered);
+        }
+        return Ok(RowResult::Valid);
+    }
+    if register(client, &row, imported_at).await? {
+        Ok(RowResult::Imported)
+    } else {
+        Ok(RowResult::AlreadyRegistered)
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+    let path = match args.into_iter().find(|arg| arg != "--dry-run") {
+        Some(path) => path,
+        None => return Err("Usage: device_import [--dry-run] <devices.csv | devices.json>".into()),
+    };
+
+    let content = std::fs::read_to_string(&path)?;
+    let rows = if path.to_lowercase().ends_with(".json") {
+        parse_json(&content)?
+    } else {
+        parse_csv(&content)?
+    };
+
+    let shared_config = load_from_env().await;
+    let client = Client::new(&shared_config);
+    let imported_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+    let mut seen = HashMap::new();
+    let mut errors = 0;
+    let mut registered = 0;
+    for (position, row) in rows.iter() {
+        let device_id = row.as_ref().map(|row| row.device_id.as_str()).unwrap_or("-");
+        let result = import_row(&client, row.clone(), position, &mut seen, dry_run, imported_at).await?;
+        if result.is_error() {
+            errors += 1;
+        } else if result == RowResult::AlreadyRegistered {
+            registered += 1;
+        }
+        println!("{} {}: {}", position, device_id, result.describe());
+    }
+
+    println!(
+        "Total: {} rows, {} {}, {} already registered, {} with errors",
+        rows.len(),
+        rows.len() - errors - registered,
+        if dry_run { "valid" } else { "imported" },
+        registered,
+        errors
+    );
+    if errors > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
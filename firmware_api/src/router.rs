@@ -0,0 +1,32 @@
+use lambda_http::{Request, RequestExt, Response, Body, Error};
+use aws_sdk_dynamodb::Client;
+use helper::admin::is_admin;
+use helper::get_user_id;
+mod endpoints;
+mod release;
+mod rollout;
+use self::endpoints::{not_implemented, admins_only, add_release, get_releases};
+use self::endpoints::{create_rollout, get_rollout, get_rollouts, update_rollout};
+
+pub async fn router(request: Request, client: &Client) -> Result<Response<Body>, Error> {
+    if !is_admin(&get_user_id(&request)) {
+        return admins_only();
+    }
+
+    match request.method().as_str() {
+        "GET" if has_param(&request, "rollout_id") => get_rollout(request, client).await,
+        "GET" if has_param(&request, "hardware_model") => get_releases(request, client).await,
+        "GET" => get_rollouts(request, client).await,
+        "POST" if has_param(&request, "rollout") => create_rollout(request, client).await,
+        "POST" => add_release(request, client).await,
+        "PATCH" => update_rollout(request, client).await,
+        _ => not_implemented(),
+    }
+}
+
+fn has_param(request: &Request, name: &str) -> bool {
+    request
+        .query_string_parameters_ref()
+        .and_then(|params| params.first(name))
+        .is_some()
+}
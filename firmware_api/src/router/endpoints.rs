@@ -0,0 +1,241 @@
+use aws_sdk_dynamodb::Client;
+use chrono::Utc;
+use helper::firmware::RolloutStatus;
+use helper::get_user_id;
+use lambda_http::{Body, Error, Request, RequestExt, Response};
+use serde::{Deserialize, Serialize};
+
+use super::release::{self, image_exists, image_key, put_release, upload_url, Release};
+use super::rollout::{self, dispatch, get_updates, put_rollout, save_rollout, DeviceUpdate, Rollout};
+
+const DEFAULT_MAX_FAILURES: u32 = 3;
+
+#[derive(Serialize, Deserialize)]
+struct ResponseBody<'a> {
+    error: bool,
+    message: &'a str,
+}
+
+#[derive(Serialize)]
+struct ReleaseResponse {
+    release: Release,
+    upload_url: String,     // PUT the image here within 15 minutes
+}
+
+#[derive(Serialize)]
+struct RolloutDetail {
+    rollout: Rollout,
+    devices: Vec<DeviceUpdate>,
+}
+
+/// Body of POST with rollout
+#[derive(Deserialize)]
+struct RolloutRequest {
+    hardware_model: String,
+    version: String,
+    percentage: u32,
+    max_failures: Option<u32>,
+}
+
+/// Body of PATCH, grow the percentage, halt or resume the rollout
+#[derive(Deserialize)]
+struct RolloutUpdate {
+    percentage: Option<u32>,
+    status: Option<RolloutStatus>,
+    max_failures: Option<u32>,
+}
+
+fn message_response(status: u16, error: bool, message: &str) -> Result<Response<Body>, Error> {
+    let response_body = ResponseBody { error, message };
+
+    let response = Response::builder()
+        .status(status)
+        .header("content-type", "text/html")
+        .body(serde_json::to_string(&response_body).unwrap().into())
+        .map_err(Box::new)?;
+    Ok(response)
+}
+
+fn json_response<T: Serialize>(body: &T) -> Result<Response<Body>, Error> {
+    let response = Response::builder()
+        .status(200)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(body).unwrap().into())
+        .map_err(Box::new)?;
+    Ok(response)
+}
+
+fn query_param<'a>(req: &'a Request, name: &str) -> Option<&'a str> {
+    req.query_string_parameters_ref().and_then(|params| params.first(name))
+}
+
+fn is_sha256(value: &str) -> bool {
+    value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Register a new firmware version and return the url to upload its image
+pub async fn add_release(req: Request, client: &Client) -> Result<Response<Body>, Error> {
+    let user_id = get_user_id(&req);
+    let (hardware_model, version) = match (query_param(&req, "hardware_model"), query_param(&req, "version")) {
+        (Some(hardware_model), Some(version)) => (hardware_model, version),
+        _ => return message_response(400, true, "Missing hardware model or version"),
+    };
+    // Versions end up in the S3 key
+    if !version.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-') {
+        return message_response(400, true, "Invalid version");
+    }
+    let sha256 = match query_param(&req, "sha256") {
+        Some(sha256) if is_sha256(sha256) => sha256.to_lowercase(),
+        _ => return message_response(400, true, "Invalid sha256"),
+    };
+
+    let release = Release {
+        hardware_model: hardware_model.to_string(),
+        version: version.to_string(),
+        s3_key: image_key(hardware_model, version),
+        sha256,
+        size: query_param(&req, "size").and_then(|v| v.parse::<i64>().ok()),
+        created_by: Some(user_id),
+        created_at: Some(Utc::now().timestamp()),
+    };
+    if !put_release(client, &release).await? {
+        return message_response(409, true, "Version already released");
+    }
+
+    let shared_config = aws_config::load_from_env().await;
+    let s3_client = aws_sdk_s3::Client::new(&shared_config);
+    let upload_url = upload_url(&s3_client, &release.s3_key).await?;
+    json_response(&ReleaseResponse { release, upload_url })
+}
+
+pub async fn get_releases(req: Request, client: &Client) -> Result<Response<Body>, Error> {
+    let hardware_model = query_param(&req, "hardware_model").unwrap();
+    json_response(&release::get_releases(client, hardware_model).await?)
+}
+
+/// Start sending a release to a percentage of the devices of its hardware model.
+/// Only the rollout is saved here, firmware_dispatch_lambda publishes the update in the background
+pub async fn create_rollout(req: Request, client: &Client) -> Result<Response<Body>, Error> {
+    let user_id = get_user_id(&req);
+
+    let body = std::str::from_utf8(req.body()).unwrap_or_default();
+    let request = match serde_json::from_str::<RolloutRequest>(body) {
+        Ok(request) if (1..=100).contains(&request.percentage) => request,
+        _ => return message_response(400, true, "Invalid rollout"),
+    };
+
+    let release = match release::get_release(client, &request.hardware_model, &request.version).await? {
+        Some(release) => release,
+        None => return message_response(404, true, "Release not found"),
+    };
+    let shared_config = aws_config::load_from_env().await;
+    let s3_client = aws_sdk_s3::Client::new(&shared_config);
+    if !image_exists(&s3_client, &release.s3_key).await? {
+        return message_response(409, true, "Firmware image not uploaded");
+    }
+    let now = Utc::now();
+    let rollout = Rollout {
+        rollout_id: format!("{:013}", now.timestamp_millis()),
+        hardware_model: request.hardware_model,
+        version: request.version,
+        percentage: request.percentage,
+        status: RolloutStatus::Active,
+        max_failures: request.max_failures.unwrap_or(DEFAULT_MAX_FAILURES),
+        sent: 0,
+        installed: 0,
+        failed: 0,
+        halted_reason: None,
+        created_by: Some(user_id),
+        created_at: Some(now.timestamp()),
+    };
+    if !put_rollout(client, &rollout).await? {
+        return message_response(409, true, "Another rollout of the hardware model is active");
+    }
+
+    dispatch(&rollout.rollout_id).await?;
+    json_response(&rollout)
+}
+
+/// Change the rollout, an active rollout is sent again in the background to the devices that didn't answer yet
+pub async fn update_rollout(req: Request, client: &Client) -> Result<Response<Body>, Error> {
+    let rollout_id = match query_param(&req, "rollout_id") {
+        Some(rollout_id) => rollout_id,
+        None => return message_response(400, true, "Missing rollout id"),
+    };
+    let body = std::str::from_utf8(req.body()).unwrap_or_default();
+    let update = match serde_json::from_str::<RolloutUpdate>(body) {
+        Ok(update) => update,
+        Err(_) => return message_response(400, true, "Invalid rollout"),
+    };
+
+    let mut rollout = match rollout::get_rollout(client, rollout_id).await? {
+        Some(rollout) => rollout,
+        None => return message_response(404, true, "Rollout not found"),
+    };
+    if let Some(percentage) = update.percentage {
+        // Devices already updated can't be taken back
+        if percentage < rollout.percentage || percentage > 100 {
+            return message_response(400, true, "Percentage can only grow up to 100");
+        }
+        rollout.percentage = percentage;
+    }
+    if let Some(max_failures) = update.max_failures {
+        rollout.max_failures = max_failures;
+    }
+    match update.status {
+        Some(RolloutStatus::Halted) => {
+            rollout.status = RolloutStatus::Halted;
+            rollout.halted_reason = Some("Halted by an admin".to_string());
+        }
+        Some(RolloutStatus::Active) => {
+            if rollout.failed >= rollout.max_failures {
+                return message_response(409, true, "Raise max_failures to resume the rollout");
+            }
+            rollout.status = RolloutStatus::Active;
+            rollout.halted_reason = None;
+        }
+        None => {}
+    }
+    if !save_rollout(client, &rollout).await? {
+        return message_response(409, true, "Another rollout of the hardware model is active");
+    }
+
+    if rollout.status == RolloutStatus::Active {
+        dispatch(rollout_id).await?;
+    }
+    json_response(&rollout)
+}
+
+/// The rollout with the state of every device
+pub async fn get_rollout(req: Request, client: &Client) -> Result<Response<Body>, Error> {
+    let rollout_id = query_param(&req, "rollout_id").unwrap();
+    let rollout = match rollout::get_rollout(client, rollout_id).await? {
+        Some(rollout) => rollout,
+        None => return message_response(404, true, "Rollout not found"),
+    };
+    let devices = get_updates(client, rollout_id).await?;
+    json_response(&RolloutDetail { rollout, devices })
+}
+
+pub async fn get_rollouts(_req: Request, client: &Client) -> Result<Response<Body>, Error> {
+    json_response(&rollout::get_rollouts(client).await?)
+}
+
+pub fn admins_only() -> Result<Response<Body>, Error> {
+    message_response(403, true, "Admins only")
+}
+
+pub fn not_implemented() -> Result<Response<Body>, Error> {
+    let response_body = ResponseBody {
+        error: true,
+        message: "API not implemented",
+    };
+
+    let response = Response::builder()
+        .status(404)
+        .header("content-type", "text/html")
+        .body(serde_json::to_string(&response_body).unwrap().into())
+        .map_err(Box::new)?;
+
+    Ok(response)
+}
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use aws_sdk_dynamodb::types::AttributeValue;
+use aws_sdk_dynamodb::Client;
+use aws_sdk_s3::presigning::PresigningConfig;
+use helper::firmware::FIRMWARE_RELEASES_TABLE;
+use lambda_http::Error;
+use serde::Serialize;
+use tokio_stream::StreamExt;
+
+const BUCKET: &str = "agromate-firmware";   // Firmware images, one folder per hardware model
+const UPLOAD_URL_EXPIRY: u64 = 15 * 60;     // Seconds the admin has to upload the image
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Release {
+    pub hardware_model: String,
+    pub version: String,
+    pub s3_key: String,
+    pub sha256: String,     // Checked by the ESP8266 before flashing
+    pub size: Option<i64>,
+    pub created_by: Option<String>,
+    pub created_at: Option<i64>,
+}
+
+impl From<&HashMap<String, AttributeValue>> for Release {
+    fn from(item: &HashMap<String, AttributeValue>) -> Self {
+        let string = |key: &str| item.get(key).and_then(|v| v.as_s().ok()).map(|v| v.to_string());
+        let number = |key: &str| {
+            item.get(key)
+                .and_then(|v| v.as_n().ok())
+                .and_then(|v| v.parse::<i64>().ok())
+        };
+        Release {
+            hardware_model: string("hardware_model").unwrap_or_default(),
+            version: string("version").unwrap_or_default(),
+            s3_key: string("s3_key").unwrap_or_default(),
+            sha256: string("sha256").unwrap_or_default(),
+            size: number("size"),
+            created_by: string("created_by"),
+            created_at: number("created_at"),
+        }
+    }
+}
+
+pub fn image_key(hardware_model: &str, version: &str) -> String {
+    format!("{}/{}.bin", hardware_model, version)
+}
+
+/// Save the release, false if the version already exists for the hardware model
+pub async fn put_release(client: &Client, release: &Release) -> Result<bool, Error> {
+    let mut request = client
+        .put_item()
+        .table_name(FIRMWARE_RELEASES_TABLE)
+        .item("hardware_model", AttributeValue::S(release.hardware_model.clone()))
+        .item("version", AttributeValue::S(release.version.clone()))
+        .item("s3_key", AttributeValue::S(release.s3_key.clone()))
+        .item("sha256", AttributeValue::S(release.sha256.clone()))
+        .condition_expression("attribute_not_exists(version)");     // Devices already flashed this version, the image can't change
+    if let Some(size) = release.size {
+        request = request.item("size", AttributeValue::N(size.to_string()));
+    }
+    if let Some(created_by) = &release.created_by {
+        request = request.item("created_by", AttributeValue::S(created_by.clone()));
+    }
+    if let Some(created_at) = release.created_at {
+        request = request.item("created_at", AttributeValue::N(created_at.to_string()));
+    }
+
+    match request.send().await {
+        Ok(_out) => Ok(true),
+        Err(err) => {
+            if err.as_service_error().map(|e| e.is_conditional_check_failed_exception()) == Some(true) {
+                Ok(false)
+            } else {
+                Err(err.into())
+            }
+        }
+    }
+}
+
+pub async fn get_release(client: &Client, hardware_model: &str, version: &str) -> Result<Option<Release>, Error> {
+    let result = client
+        .get_item()
+        .table_name(FIRMWARE_RELEASES_TABLE)
+        .key("hardware_model", AttributeValue::S(hardware_model.to_string()))
+        .key("version", AttributeValue::S(version.to_string()))
+        .send()
+        .await?;
+    Ok(result.item().map(Release::from))
+}
+
+pub async fn get_releases(client: &Client, hardware_model: &str) -> Result<Vec<Release>, Error> {
+    let items: Result<Vec<_>, _> = client
+        .query()
+        .table_name(FIRMWARE_RELEASES_TABLE)
+        .key_condition_expression("#hardware_model = :hardware_model")
+        .expression_attribute_names("#hardware_model", "hardware_model")
+        .expression_attribute_values(":hardware_model", AttributeValue::S(hardware_model.to_string()))
+        .into_paginator()
+        .items()
+        .send()
+        .collect()
+        .await;
+    Ok(items?.iter().map(Release::from).collect())
+}
+
+/// The release is saved before the upload, a rollout can start only when the image is in the bucket
+pub async fn image_exists(s3_client: &aws_sdk_s3::Client, s3_key: &str) -> Result<bool, Error> {
+    let result = s3_client.head_object().bucket(BUCKET).key(s3_key).send().await;
+    match result {
+        Ok(_out) => Ok(true),
+        Err(err) => {
+            if err.as_service_error().map(|e| e.is_not_found()) == Some(true) {
+                Ok(false)
+            } else {
+                Err(err.into())
+            }
+        }
+    }
+}
+
+pub async fn upload_url(s3_client: &aws_sdk_s3::Client, s3_key: &str) -> Result<String, Error> {
+    let object = s3_client
+        .put_object()
+        .bucket(BUCKET)
+        .key(s3_key)
+        .presigned(PresigningConfig::expires_in(Duration::new(UPLOAD_URL_EXPIRY, 0)).unwrap())
+        .await?;
+    Ok(object.uri().to_string())
+}
@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+use std::env;
+
+use aws_sdk_dynamodb::error::SdkError;
+use aws_sdk_dynamodb::operation::transact_write_items::TransactWriteItemsError;
+use aws_sdk_dynamodb::types::{AttributeValue, Delete, Put, TransactWriteItem, Update};
+use aws_sdk_dynamodb::Client;
+use aws_sdk_lambda::primitives::Blob;
+use aws_sdk_lambda::types::InvocationType;
+use helper::firmware::{RolloutStatus, UpdateStatus};
+use helper::firmware::{FIRMWARE_ACTIVE_ROLLOUTS_TABLE, FIRMWARE_ROLLOUTS_TABLE, FIRMWARE_UPDATES_TABLE};
+use lambda_http::Error;
+use serde::Serialize;
+use tokio_stream::StreamExt;
+
+/// Release sent to a percentage of the devices of its hardware model.
+/// Counters are updated by firmware_report_lambda with the reports of the devices
+#[derive(Serialize, Clone, Debug)]
+pub struct Rollout {
+    pub rollout_id: String,
+    pub hardware_model: String,
+    pub version: String,
+    pub percentage: u32,
+    pub status: RolloutStatus,
+    pub max_failures: u32,      // Failed devices after which the rollout is halted
+    pub sent: u32,
+    pub installed: u32,
+    pub failed: u32,
+    pub halted_reason: Option<String>,
+    pub created_by: Option<String>,
+    pub created_at: Option<i64>,
+}
+
+/// State of the rollout on a device
+#[derive(Serialize, Clone, Debug)]
+pub struct DeviceUpdate {
+    pub device_id: String,
+    pub status: UpdateStatus,
+    pub sent_at: Option<i64>,
+    pub updated_at: Option<i64>,    // Time of the last report
+    pub error: Option<String>,      // Reported by the device when the update fails
+}
+
+fn attribute_to_string(item: &HashMap<String, AttributeValue>, key: &str) -> Option<String> {
+    item.get(key).and_then(|v| v.as_s().ok()).map(|v| v.to_string())
+}
+
+fn attribute_to_i64(item: &HashMap<String, AttributeValue>, key: &str) -> Option<i64> {
+    item.get(key)
+        .and_then(|v| v.as_n().ok())
+        .and_then(|v| v.parse::<i64>().ok())
+}
+
+impl From<&HashMap<String, AttributeValue>> for Rollout {
+    fn from(item: &HashMap<String, AttributeValue>) -> Self {
+        let counter = |key: &str| attribute_to_i64(item, key).unwrap_or_default() as u32;
+        Rollout {
+            rollout_id: attribute_to_string(item, "rollout_id").unwrap_or_default(),
+            hardware_model: attribute_to_string(item, "hardware_model").unwrap_or_default(),
+            version: attribute_to_string(item, "version").unwrap_or_default(),
+            percentage: counter("percentage"),
+            status: attribute_to_string(item, "status")
+                .and_then(|v| RolloutStatus::parse(&v))
+                .unwrap_or(RolloutStatus::Halted),
+            max_failures: counter("max_failures"),
+            sent: counter("sent"),
+            installed: counter("installed"),
+            failed: counter("failed"),
+            halted_reason: attribute_to_string(item, "halted_reason"),
+            created_by: attribute_to_string(item, "created_by"),
+            created_at: attribute_to_i64(item, "created_at"),
+        }
+    }
+}
+
+impl From<&HashMap<String, AttributeValue>> for DeviceUpdate {
+    fn from(item: &HashMap<String, AttributeValue>) -> Self {
+        DeviceUpdate {
+            device_id: attribute_to_string(item, "device_id").unwrap_or_default(),
+            status: attribute_to_string(item, "status")
+                .and_then(|v| UpdateStatus::parse(&v))
+                .unwrap_or(UpdateStatus::Sent),
+            sent_at: attribute_to_i64(item, "sent_at"),
+            updated_at: attribute_to_i64(item, "updated_at"),
+            error: attribute_to_string(item, "error"),
+        }
+    }
+}
+
+/// Take the lock of the hardware model for the rollout, fails if another rollout holds it
+fn lock_hardware_model(rollout: &Rollout) -> TransactWriteItem {
+    let lock = Put::builder()
+        .table_name(FIRMWARE_ACTIVE_ROLLOUTS_TABLE)
+        .item("hardware_model", AttributeValue::S(rollout.hardware_model.clone()))
+        .item("rollout_id", AttributeValue::S(rollout.rollout_id.clone()))
+        .condition_expression("attribute_not_exists(hardware_model) OR rollout_id = :rollout_id")
+        .expression_attribute_values(":rollout_id", AttributeValue::S(rollout.rollout_id.clone()))
+        .build();
+    TransactWriteItem::builder().put(lock).build()
+}
+
+/// Release the lock of the hardware model if the rollout holds it
+fn unlock_hardware_model(rollout: &Rollout) -> TransactWriteItem {
+    let unlock = Delete::builder()
+        .table_name(FIRMWARE_ACTIVE_ROLLOUTS_TABLE)
+        .key("hardware_model", AttributeValue::S(rollout.hardware_model.clone()))
+        .condition_expression("attribute_not_exists(hardware_model) OR rollout_id = :rollout_id")
+        .expression_attribute_values(":rollout_id", AttributeValue::S(rollout.rollout_id.clone()))
+        .build();
+    TransactWriteItem::builder().delete(unlock).build()
+}
+
+/// True if the transaction was canceled by the lock, always the first item of the transaction
+fn is_locked(err: &SdkError<TransactWriteItemsError>) -> bool {
+    err.as_service_error()
+        .and_then(|e| match e {
+            TransactWriteItemsError::TransactionCanceledException(canceled) => canceled.cancellation_reasons(),
+            _ => None,
+        })
+        .and_then(|reasons| reasons.first())
+        .and_then(|reason| reason.code())
+        == Some("ConditionalCheckFailed")
+}
+
+/// Save a new active rollout, false if another rollout of the hardware model is active.
+/// The lock is taken in the same transaction, so of two concurrent rollouts only one is saved
+pub async fn put_rollout(client: &Client, rollout: &Rollout) -> Result<bool, Error> {
+    let mut put = Put::builder()
+        .table_name(FIRMWARE_ROLLOUTS_TABLE)
+        .item("rollout_id", AttributeValue::S(rollout.rollout_id.clone()))
+        .item("hardware_model", AttributeValue::S(rollout.hardware_model.clone()))
+        .item("version", AttributeValue::S(rollout.version.clone()))
+        .item("percentage", AttributeValue::N(rollout.percentage.to_string()))
+        .item("status", AttributeValue::S(rollout.status.as_str().to_string()))
+        .item("max_failures", AttributeValue::N(rollout.max_failures.to_string()))
+        .item("sent", AttributeValue::N("0".to_string()))
+        .item("installed", AttributeValue::N("0".to_string()))
+        .item("failed", AttributeValue::N("0".to_string()))
+        .condition_expression("attribute_not_exists(rollout_id)");
+    if let Some(created_by) = &rollout.created_by {
+        put = put.item("created_by", AttributeValue::S(created_by.clone()));
+    }
+    if let Some(created_at) = rollout.created_at {
+        put = put.item("created_at", AttributeValue::N(created_at.to_string()));
+    }
+
+    let result = client
+        .transact_write_items()
+        .transact_items(lock_hardware_model(rollout))
+        .transact_items(TransactWriteItem::builder().put(put.build()).build())
+        .send()
+        .await;
+    match result {
+        Ok(_out) => Ok(true),
+        Err(err) if is_locked(&err) => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub async fn get_rollout(client: &Client, rollout_id: &str) -> Result<Option<Rollout>, Error> {
+    let result = client
+        .get_item()
+        .table_name(FIRMWARE_ROLLOUTS_TABLE)
+        .key("rollout_id", AttributeValue::S(rollout_id.to_string()))
+        .send()
+        .await?;
+    Ok(result.item().map(Rollout::from))
+}
+
+/// Every rollout, there are only a few per release
+pub async fn get_rollouts(client: &Client) -> Result<Vec<Rollout>, Error> {
+    let items: Result<Vec<_>, _> = client
+        .scan()
+        .table_name(FIRMWARE_ROLLOUTS_TABLE)
+        .into_paginator()
+        .items()
+        .send()
+        .collect()
+        .await;
+    let mut rollouts: Vec<Rollout> = items?.iter().map(Rollout::from).collect();
+    rollouts.sort_by(|a, b| b.rollout_id.cmp(&a.rollout_id));  // Newest first
+    Ok(rollouts)
+}
+
+/// Save percentage, status and max_failures changed by the admin, false if the rollout is
+/// resumed while another rollout of the hardware model is active. Halting releases the lock
+pub async fn save_rollout(client: &Client, rollout: &Rollout) -> Result<bool, Error> {
+    let mut update = Update::builder()
+        .table_name(FIRMWARE_ROLLOUTS_TABLE)
+        .key("rollout_id", AttributeValue::S(rollout.rollout_id.clone()))
+        .condition_expression("attribute_exists(rollout_id)")
+        .expression_attribute_names("#status", "status")
+        .expression_attribute_values(":percentage", AttributeValue::N(rollout.percentage.to_string()))
+        .expression_attribute_values(":status", AttributeValue::S(rollout.status.as_str().to_string()))
+        .expression_attribute_values(":max_failures", AttributeValue::N(rollout.max_failures.to_string()));
+
+    let mut update_expression = "SET percentage = :percentage, #status = :status, max_failures = :max_failures".to_string();
+    match &rollout.halted_reason {
+        Some(halted_reason) => {
+            update_expression.push_str(", halted_reason = :halted_reason");
+            update = update.expression_attribute_values(":halted_reason", AttributeValue::S(halted_reason.clone()));
+        }
+        None => update_expression.push_str(" REMOVE halted_reason"),
+    }
+    let lock = match rollout.status {
+        RolloutStatus::Active => lock_hardware_model(rollout),
+        RolloutStatus::Halted => unlock_hardware_model(rollout),
+    };
+
+    let result = client
+        .transact_write_items()
+        .transact_items(lock)
+        .transact_items(TransactWriteItem::builder().update(update.update_expression(update_expression).build()).build())
+        .send()
+        .await;
+    match result {
+        Ok(_out) => Ok(true),
+        Err(err) if is_locked(&err) => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// State of the rollout on every device that received it
+pub async fn get_updates(client: &Client, rollout_id: &str) -> Result<Vec<DeviceUpdate>, Error> {
+    let items: Result<Vec<_>, _> = client
+        .query()
+        .table_name(FIRMWARE_UPDATES_TABLE)
+        .key_condition_expression("#rollout_id = :rollout_id")
+        .expression_attribute_names("#rollout_id", "rollout_id")
+        .expression_attribute_values(":rollout_id", AttributeValue::S(rollout_id.to_string()))
+        .into_paginator()
+        .items()
+        .send()
+        .collect()
+        .await;
+    Ok(items?.iter().map(DeviceUpdate::from).collect())
+}
+
+/// Event of firmware_dispatch_lambda
+#[derive(Serialize)]
+struct DispatchRequest<'a> {
+    rollout_id: &'a str,
+}
+
+/// Name of the firmware_dispatch_lambda, can be changed with the FIRMWARE_DISPATCH_FUNCTION environment variable
+fn dispatch_function() -> String {
+    env::var("FIRMWARE_DISPATCH_FUNCTION").unwrap_or("firmware_dispatch_lambda".to_string())
+}
+
+/// Ask firmware_dispatch_lambda to send the rollout to its devices.
+/// The invocation is asynchronous, publishing to a whole fleet doesn't fit in the API Gateway timeout
+pub async fn dispatch(rollout_id: &str) -> Result<(), Error> {
+    let shared_config = aws_config::load_from_env().await;
+    let lambda_client = aws_sdk_lambda::Client::new(&shared_config);
+    lambda_client
+        .invoke()
+        .function_name(dispatch_function())
+        .invocation_type(InvocationType::Event)
+        .payload(Blob::new(serde_json::to_string(&DispatchRequest { rollout_id }).unwrap()))
+        .send()
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,336 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use aws_config::load_from_env;
+use aws_sdk_dynamodb::types::{AttributeValue, Put, TransactWriteItem, Update};
+use aws_sdk_dynamodb::Client;
+use aws_sdk_iotdataplane::primitives::Blob;
+use aws_sdk_s3::presigning::PresigningConfig;
+use chrono::Utc;
+use helper::firmware::{rollout_bucket, update_topic, RolloutStatus, UpdateStatus};
+use helper::firmware::{FIRMWARE_RELEASES_TABLE, FIRMWARE_ROLLOUTS_TABLE, FIRMWARE_UPDATES_TABLE};
+use lambda_runtime::{run, service_fn, Error, LambdaEvent};
+use serde::{Deserialize, Serialize};
+use tokio_stream::StreamExt;
+
+const DEVICE_TABLE_NAME: &str = "devices";  // firmware_version is updated by mqtt_month_media_processor
+const BUCKET: &str = "agromate-firmware";   // Same bucket of firmware_api
+const DOWNLOAD_URL_EXPIRY: u64 = 24 * 60 * 60;  // The device may be offline when the update is sent
+
+/*
+    Invoked asynchronously by firmware_api when a rollout is created or changed:
+
+    {
+        "rollout_id": "1697712000123"
+    }
+
+    The rollout and its release are read again here, the admin may have halted it in the meantime.
+*/
+#[derive(Deserialize)]
+struct Request {
+    rollout_id: String,
+}
+
+#[derive(Serialize)]
+struct Response {
+    req_id: String,
+    msg: String,
+}
+
+/*
+    Published to the device, the ESP8266 downloads the image, checks the hash and reports on
+    sensor/firmware/{device_id}/report:
+
+    {
+        "rollout_id": "1697712000123",
+        "version": "1.3.0",
+        "url": "https://agromate-firmware.s3.eu-central-1.amazonaws.com/ESP8266/1.3.0.bin?X-Amz-...",
+        "sha256": "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08",
+        "size": 412345
+    }
+*/
+#[derive(Serialize)]
+struct UpdateMessage<'a> {
+    rollout_id: &'a str,
+    version: &'a str,
+    url: &'a str,
+    sha256: &'a str,
+    size: Option<i64>,
+}
+
+/// Fields of the rollout needed to pick the devices
+struct Rollout {
+    rollout_id: String,
+    hardware_model: String,
+    version: String,
+    percentage: u32,
+    status: RolloutStatus,
+}
+
+/// Fields of the release sent to the devices
+struct Release {
+    s3_key: String,
+    sha256: String,
+    size: Option<i64>,
+}
+
+fn attribute_to_string(item: &HashMap<String, AttributeValue>, key: &str) -> Option<String> {
+    item.get(key).and_then(|v| v.as_s().ok()).map(|v| v.to_string())
+}
+
+fn attribute_to_i64(item: &HashMap<String, AttributeValue>, key: &str) -> Option<i64> {
+    item.get(key)
+        .and_then(|v| v.as_n().ok())
+        .and_then(|v| v.parse::<i64>().ok())
+}
+
+impl From<&HashMap<String, AttributeValue>> for Rollout {
+    fn from(item: &HashMap<String, AttributeValue>) -> Self {
+        Rollout {
+            rollout_id: attribute_to_string(item, "rollout_id").unwrap_or_default(),
+            hardware_model: attribute_to_string(item, "hardware_model").unwrap_or_default(),
+            version: attribute_to_string(item, "version").unwrap_or_default(),
+            percentage: attribute_to_i64(item, "percentage").unwrap_or_default() as u32,
+            status: attribute_to_string(item, "status")
+                .and_then(|v| RolloutStatus::parse(&v))
+                .unwrap_or(RolloutStatus::Halted),
+        }
+    }
+}
+
+impl From<&HashMap<String, AttributeValue>> for Release {
+    fn from(item: &HashMap<String, AttributeValue>) -> Self {
+        Release {
+            s3_key: attribute_to_string(item, "s3_key").unwrap_or_default(),
+            sha256: attribute_to_string(item, "sha256").unwrap_or_default(),
+            size: attribute_to_i64(item, "size"),
+        }
+    }
+}
+
+async fn get_rollout(client: &Client, rollout_id: &str) -> Result<Option<Rollout>, Error> {
+    let result = client
+        .get_item()
+        .table_name(FIRMWARE_ROLLOUTS_TABLE)
+        .key("rollout_id", AttributeValue::S(rollout_id.to_string()))
+        .consistent_read(true)
+        .send()
+        .await?;
+    Ok(result.item().map(Rollout::from))
+}
+
+async fn get_release(client: &Client, hardware_model: &str, version: &str) -> Result<Option<Release>, Error> {
+    let result = client
+        .get_item()
+        .table_name(FIRMWARE_RELEASES_TABLE)
+        .key("hardware_model", AttributeValue::S(hardware_model.to_string()))
+        .key("version", AttributeValue::S(version.to_string()))
+        .send()
+        .await?;
+    Ok(result.item().map(Release::from))
+}
+
+async fn download_url(s3_client: &aws_sdk_s3::Client, s3_key: &str) -> Result<String, Error> {
+    let object = s3_client
+        .get_object()
+        .bucket(BUCKET)
+        .key(s3_key)
+        .presigned(PresigningConfig::expires_in(Duration::new(DOWNLOAD_URL_EXPIRY, 0)).unwrap())
+        .await?;
+    Ok(object.uri().to_string())
+}
+
+/// State of the rollout on every device that already received it
+async fn get_updates(client: &Client, rollout_id: &str) -> Result<HashMap<String, UpdateStatus>, Error> {
+    let items: Result<Vec<_>, _> = client
+        .query()
+        .table_name(FIRMWARE_UPDATES_TABLE)
+        .key_condition_expression("#rollout_id = :rollout_id")
+        .expression_attribute_names("#rollout_id", "rollout_id")
+        .expression_attribute_values(":rollout_id", AttributeValue::S(rollout_id.to_string()))
+        .into_paginator()
+        .items()
+        .send()
+        .collect()
+        .await;
+    Ok(items?
+        .iter()
+        .filter_map(|item| {
+            let device_id = attribute_to_string(item, "device_id")?;
+            let status = attribute_to_string(item, "status")
+                .and_then(|v| UpdateStatus::parse(&v))
+                .unwrap_or(UpdateStatus::Sent);
+            Some((device_id, status))
+        })
+        .collect())
+}
+
+/// Devices of the hardware model still in use
+async fn get_devices(client: &Client, hardware_model: &str) -> Result<Vec<HashMap<String, AttributeValue>>, Error> {
+    let items: Result<Vec<_>, _> = client
+        .scan()
+        .table_name(DEVICE_TABLE_NAME)
+        .filter_expression("hardware_model = :hardware_model AND (attribute_not_exists(#status) OR #status <> :retired)")
+        .expression_attribute_names("#status", "status")
+        .expression_attribute_values(":hardware_model", AttributeValue::S(hardware_model.to_string()))
+        .expression_attribute_values(":retired", AttributeValue::S("retired".to_string()))
+        .into_paginator()
+        .items()
+        .send()
+        .collect()
+        .await;
+    Ok(items?)
+}
+
+/// Save the device as sent, true if it wasn't part of the rollout yet and the sent counter grew.
+/// The new device row and the counter are written in the same transaction, a retry never counts twice
+async fn mark_sent(client: &Client, rollout_id: &str, device_id: &str) -> Result<bool, Error> {
+    let now = AttributeValue::N(Utc::now().timestamp().to_string());
+    let sent = AttributeValue::S(UpdateStatus::Sent.as_str().to_string());
+
+    let put = Put::builder()
+        .table_name(FIRMWARE_UPDATES_TABLE)
+        .item("rollout_id", AttributeValue::S(rollout_id.to_string()))
+        .item("device_id", AttributeValue::S(device_id.to_string()))
+        .item("status", sent.clone())
+        .item("sent_at", now.clone())
+        .condition_expression("attribute_not_exists(device_id)")
+        .build();
+    let count = Update::builder()
+        .table_name(FIRMWARE_ROLLOUTS_TABLE)
+        .key("rollout_id", AttributeValue::S(rollout_id.to_string()))
+        .update_expression("ADD sent :one")
+        .condition_expression("attribute_exists(rollout_id)")
+        .expression_attribute_values(":one", AttributeValue::N("1".to_string()))
+        .build();
+    let result = client
+        .transact_write_items()
+        .transact_items(TransactWriteItem::builder().put(put).build())
+        .transact_items(TransactWriteItem::builder().update(count).build())
+        .send()
+        .await;
+    match result {
+        Ok(_out) => return Ok(true),
+        Err(err) => {
+            if err.as_service_error().map(|e| e.is_transaction_canceled_exception()) != Some(true) {
+                return Err(err.into());
+            }
+        }
+    }
+
+    // Already part of the rollout, only the time of the new message is saved.
+    // A report may have arrived after the update was published, it is left alone
+    let result = client
+        .update_item()
+        .table_name(FIRMWARE_UPDATES_TABLE)
+        .key("rollout_id", AttributeValue::S(rollout_id.to_string()))
+        .key("device_id", AttributeValue::S(device_id.to_string()))
+        .update_expression("SET sent_at = :now")
+        .condition_expression("#status = :sent")
+        .expression_attribute_names("#status", "status")
+        .expression_attribute_values(":now", now)
+        .expression_attribute_values(":sent", sent)
+        .send()
+        .await;
+    match result {
+        Ok(_out) => Ok(false),
+        Err(err) => {
+            if err.as_service_error().map(|e| e.is_conditional_check_failed_exception()) == Some(true) {
+                Ok(false)
+            } else {
+                Err(err.into())
+            }
+        }
+    }
+}
+
+/// Send the update to the devices inside the percentage of the rollout.
+/// Devices that didn't answer yet get it again with a new download url, the others are left alone.
+/// Returns how many devices the update was published to
+async fn dispatch(client: &Client, rollout: &Rollout, release: &Release) -> Result<usize, Error> {
+    let shared_config = load_from_env().await;
+    let s3_client = aws_sdk_s3::Client::new(&shared_config);
+    let iot_data_client = aws_sdk_iotdataplane::Client::new(&shared_config);
+
+    let updates = get_updates(client, &rollout.rollout_id).await?;
+
+    let mut published = 0;
+    for device in get_devices(client, &rollout.hardware_model).await? {
+        let device_id = match attribute_to_string(&device, "device_id") {
+            Some(device_id) => device_id,
+            None => continue,
+        };
+        if attribute_to_string(&device, "firmware_version").as_deref() == Some(rollout.version.as_str()) {
+            continue;
+        }
+        if rollout_bucket(&rollout.rollout_id, &device_id) >= rollout.percentage {
+            continue;
+        }
+        if matches!(updates.get(&device_id), Some(status) if *status != UpdateStatus::Sent) {
+            continue;   // Already downloading, installed or failed
+        }
+
+        let url = download_url(&s3_client, &release.s3_key).await?;
+        let message = UpdateMessage {
+            rollout_id: &rollout.rollout_id,
+            version: &rollout.version,
+            url: &url,
+            sha256: &release.sha256,
+            size: release.size,
+        };
+        // Not retained, the download url expires and the device must not flash twice
+        let result = iot_data_client
+            .publish()
+            .topic(update_topic(&device_id))
+            .qos(1)
+            .retain(false)
+            .payload(Blob::new(serde_json::to_string(&message).unwrap()))
+            .send()
+            .await;
+        if let Err(err) = result {
+            println!("{:?}", err);
+            continue;
+        }
+
+        mark_sent(client, &rollout.rollout_id, &device_id).await?;
+        published += 1;
+    }
+    Ok(published)
+}
+
+async fn function_handler(event: LambdaEvent<Request>) -> Result<Response, Error> {
+    let shared_config = load_from_env().await;
+    let client = Client::new(&shared_config);
+    let rollout_id = &event.payload.rollout_id;
+
+    let msg = match get_rollout(&client, rollout_id).await? {
+        None => format!("Rollout {} not found.", rollout_id),
+        Some(rollout) if rollout.status != RolloutStatus::Active => format!("Rollout {} is halted.", rollout_id),
+        Some(rollout) => match get_release(&client, &rollout.hardware_model, &rollout.version).await? {
+            None => format!("Release {} of {} not found.", rollout.version, rollout.hardware_model),
+            Some(release) => {
+                let published = dispatch(&client, &rollout, &release).await?;
+                format!("Rollout {} sent to {} devices.", rollout_id, published)
+            }
+        },
+    };
+
+    let resp = Response {
+        req_id: event.context.request_id,
+        msg,
+    };
+    Ok(resp)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::ERROR)
+        // disable printing the name of the module in every log line.
+        .with_target(false)
+        // disabling time is handy because CloudWatch will add the ingestion time.
+        .without_time()
+        .init();
+
+    run(service_fn(function_handler)).await
+}
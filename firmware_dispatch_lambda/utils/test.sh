@@ -0,0 +1,2 @@
+#!/bin/bash
+cargo lambda invoke --data-file utils/test.json
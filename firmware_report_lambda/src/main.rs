@@ -0,0 +1,207 @@
+use aws_config::load_from_env;
+use aws_sdk_dynamodb::types::{AttributeValue, Delete, TransactWriteItem, Update};
+use aws_sdk_dynamodb::Client;
+use chrono::Utc;
+use helper::firmware::{RolloutStatus, UpdateStatus, FIRMWARE_ACTIVE_ROLLOUTS_TABLE, FIRMWARE_ROLLOUTS_TABLE, FIRMWARE_UPDATES_TABLE};
+use lambda_runtime::{run, service_fn, Error, LambdaEvent};
+use serde::{Deserialize, Serialize};
+
+/*
+    The ESP8266 reports the progress of the update on sensor/firmware/{device_id}/report:
+
+    {
+        "rollout_id": "1697712000123",
+        "status": "failed",
+        "error": "sha256 mismatch"
+    }
+
+    status is downloading, installed or failed, sent is only set when the update is published.
+    The IoT rule adds the device_id taken from the topic:
+
+    SELECT *, topic(3) AS device_id FROM 'sensor/firmware/+/report'
+*/
+#[derive(Deserialize)]
+struct Request {
+    device_id: String,
+    rollout_id: String,
+    status: UpdateStatus,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Response {
+    req_id: String,
+    msg: String,
+}
+
+/// Save the report on the device row and count installed and failed devices on the rollout in the
+/// same transaction, false if the device is not part of the rollout or already finished
+async fn save_report(client: &Client, report: &Request) -> Result<bool, Error> {
+    let mut update_expression = "SET #status = :status, updated_at = :now".to_string();
+    let mut save = Update::builder()
+        .table_name(FIRMWARE_UPDATES_TABLE)
+        .key("rollout_id", AttributeValue::S(report.rollout_id.clone()))
+        .key("device_id", AttributeValue::S(report.device_id.clone()))
+        // Installed and failed are final, so every device is counted once
+        .condition_expression("attribute_exists(device_id) AND #status <> :installed AND #status <> :failed")
+        .expression_attribute_names("#status", "status")
+        .expression_attribute_values(":status", AttributeValue::S(report.status.as_str().to_string()))
+        .expression_attribute_values(":now", AttributeValue::N(Utc::now().timestamp().to_string()))
+        .expression_attribute_values(":installed", AttributeValue::S(UpdateStatus::Installed.as_str().to_string()))
+        .expression_attribute_values(":failed", AttributeValue::S(UpdateStatus::Failed.as_str().to_string()));
+    if let Some(error) = &report.error {
+        update_expression.push_str(", #error = :error");
+        save = save
+            .expression_attribute_names("#error", "error")
+            .expression_attribute_values(":error", AttributeValue::S(error.clone()));
+    }
+
+    let mut request = client
+        .transact_write_items()
+        .transact_items(TransactWriteItem::builder().update(save.update_expression(update_expression).build()).build());
+    let counter = match report.status {
+        UpdateStatus::Installed => Some("installed"),
+        UpdateStatus::Failed => Some("failed"),
+        _ => None,
+    };
+    if let Some(counter) = counter {
+        let count = Update::builder()
+            .table_name(FIRMWARE_ROLLOUTS_TABLE)
+            .key("rollout_id", AttributeValue::S(report.rollout_id.clone()))
+            .update_expression("ADD #counter :one")
+            .condition_expression("attribute_exists(rollout_id)")
+            .expression_attribute_names("#counter", counter)
+            .expression_attribute_values(":one", AttributeValue::N("1".to_string()))
+            .build();
+        request = request.transact_items(TransactWriteItem::builder().update(count).build());
+    }
+
+    match request.send().await {
+        Ok(_out) => Ok(true),
+        Err(err) => {
+            if err.as_service_error().map(|e| e.is_transaction_canceled_exception()) == Some(true) {
+                Ok(false)
+            } else {
+                Err(err.into())
+            }
+        }
+    }
+}
+
+/// Failed devices, max_failures and hardware model of the rollout, None if it doesn't exist
+async fn get_failures(client: &Client, rollout_id: &str) -> Result<Option<(i64, i64, String)>, Error> {
+    let result = client
+        .get_item()
+        .table_name(FIRMWARE_ROLLOUTS_TABLE)
+        .key("rollout_id", AttributeValue::S(rollout_id.to_string()))
+        .consistent_read(true)
+        .send()
+        .await?;
+
+    Ok(result.item().map(|item| {
+        let number = |key: &str| {
+            item.get(key)
+                .and_then(|v| v.as_n().ok())
+                .and_then(|v| v.parse::<i64>().ok())
+                .unwrap_or_default()
+        };
+        let hardware_model = item
+            .get("hardware_model")
+            .and_then(|v| v.as_s().ok())
+            .cloned()
+            .unwrap_or_default();
+        (number("failed"), number("max_failures"), hardware_model)
+    }))
+}
+
+/// Stop the rollout, the devices already updating finish, no new device gets the update
+/// and the lock of the hardware model is released in the same transaction, so a new rollout can start
+async fn halt_rollout(client: &Client, rollout_id: &str, hardware_model: &str, failed: i64) -> Result<bool, Error> {
+    let halt = Update::builder()
+        .table_name(FIRMWARE_ROLLOUTS_TABLE)
+        .key("rollout_id", AttributeValue::S(rollout_id.to_string()))
+        .update_expression("SET #status = :halted, halted_reason = :reason")
+        .condition_expression("#status = :active")
+        .expression_attribute_names("#status", "status")
+        .expression_attribute_values(":halted", AttributeValue::S(RolloutStatus::Halted.as_str().to_string()))
+        .expression_attribute_values(":active", AttributeValue::S(RolloutStatus::Active.as_str().to_string()))
+        .expression_attribute_values(":reason", AttributeValue::S(format!("{} devices failed the update", failed)))
+        .build();
+    let unlock = Delete::builder()
+        .table_name(FIRMWARE_ACTIVE_ROLLOUTS_TABLE)
+        .key("hardware_model", AttributeValue::S(hardware_model.to_string()))
+        .condition_expression("attribute_not_exists(hardware_model) OR rollout_id = :rollout_id")
+        .expression_attribute_values(":rollout_id", AttributeValue::S(rollout_id.to_string()))
+        .build();
+
+    let result = client
+        .transact_write_items()
+        .transact_items(TransactWriteItem::builder().update(halt).build())
+        .transact_items(TransactWriteItem::builder().delete(unlock).build())
+        .send()
+        .await;
+
+    match result {
+        Ok(_out) => Ok(true),
+        Err(err) => {
+            if err.as_service_error().map(|e| e.is_transaction_canceled_exception()) == Some(true) {
+                Ok(false)   // Already halted
+            } else {
+                Err(err.into())
+            }
+        }
+    }
+}
+
+async fn function_handler(event: LambdaEvent<Request>) -> Result<Response, Error> {
+    let shared_config = load_from_env().await;
+    let client = Client::new(&shared_config);
+    let report = &event.payload;
+
+    // sent is set by firmware_dispatch_lambda, a device reporting it would get the update published again
+    if report.status == UpdateStatus::Sent {
+        return Ok(Response {
+            req_id: event.context.request_id,
+            msg: format!("Report of {} ignored, devices can't report sent.", report.device_id),
+        });
+    }
+
+    let saved = save_report(&client, report).await?;
+
+    // Checked also when the report was already saved, a retry after a failed halt still stops the rollout
+    let mut halted = false;
+    if report.status == UpdateStatus::Failed {
+        if let Some((failed, max_failures, hardware_model)) = get_failures(&client, &report.rollout_id).await? {
+            if failed >= max_failures {
+                halted = halt_rollout(&client, &report.rollout_id, &hardware_model, failed).await?;
+            }
+        }
+    }
+
+    let msg = if halted {
+        format!("{} failed, rollout {} halted.", report.device_id, report.rollout_id)
+    } else if !saved {
+        format!("Report of {} ignored.", report.device_id)
+    } else {
+        format!("{} is {}.", report.device_id, report.status.as_str())
+    };
+
+    let resp = Response {
+        req_id: event.context.request_id,
+        msg,
+    };
+    Ok(resp)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::ERROR)
+        // disable printing the name of the module in every log line.
+        .with_target(false)
+        // disabling time is handy because CloudWatch will add the ingestion time.
+        .without_time()
+        .init();
+
+    run(service_fn(function_handler)).await
+}
@@ -1,9 +1,12 @@
-use aws_sdk_dynamodb::{operation::query::QueryOutput, types::AttributeValue, Client};
+use aws_sdk_dynamodb::{types::AttributeValue, Client};
 use lambda_http::{run, service_fn, Body, Error, Request, RequestExt, Response};
 use std::collections::HashMap;
 use helper::get_user_id;
+use helper::sharing::DEVICE_GRANTS_TABLE;
 
-const TABLE_NAME: &str = "sensor_measuration";
+const TABLE_NAME: &str = "sensor_telemetry"; // Written by mqtt_month_media_processor, keyed by uuid + timestamp
+const DEVICE_TABLE: &str = "devices";
+const MAX_READINGS: usize = 1000;   // Newest readings returned, older ones are in the rollups
 
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 struct SensorData {
@@ -12,21 +15,67 @@ struct SensorData {
     hour: bool,
     humidity: String,
     media_month: bool,
-    soil_humidity: String,
     temperature: String,
+    // Optional channels, older firmwares don't send them
+    soil_humidity: Option<String>,
+    light: Option<String>,
+    battery: Option<String>,
+    rssi: Option<String>,
+    firmware_version: Option<String>,
 }
 
-async fn get_list(client: &Client, uid: &str) -> QueryOutput {
-    client
-        .query()
-        .table_name(TABLE_NAME)
-        .index_name("uuid-index") // Use the GSI to query only UUID in table. Since we use a "fake" composite key uuid_timestamp in table
-        .key_condition_expression("#uuid_attribute = :uuid")
-        .expression_attribute_names("#uuid_attribute", "uuid")
-        .expression_attribute_values(":uuid", AttributeValue::S(uid.to_string()))
+fn optional_number(measurement: &HashMap<String, AttributeValue>, key: &str) -> Option<String> {
+    measurement.get(key).and_then(|v| v.as_n().ok()).map(|v| v.to_string())
+}
+
+/// Newest MAX_READINGS readings of the sensor, oldest first.
+/// A query returns at most 1 MB, so pages are read until there are enough readings
+async fn get_list(client: &Client, uid: &str) -> Result<Vec<HashMap<String, AttributeValue>>, Error> {
+    let mut items = Vec::new();
+    let mut start_key = None;
+    loop {
+        let output = client
+            .query()
+            .table_name(TABLE_NAME)
+            .key_condition_expression("#uuid_attribute = :uuid")
+            .expression_attribute_names("#uuid_attribute", "uuid")
+            .expression_attribute_values(":uuid", AttributeValue::S(uid.to_string()))
+            .scan_index_forward(false)
+            .limit((MAX_READINGS - items.len()) as i32)
+            .set_exclusive_start_key(start_key)
+            .send()
+            .await?;
+        items.extend(output.items().unwrap_or_default().iter().cloned());
+        start_key = output.last_evaluated_key().cloned();
+        if start_key.is_none() || items.len() >= MAX_READINGS {
+            break;
+        }
+    }
+    items.reverse();
+    Ok(items)
+}
+
+/// Readings are visible to the owner of the sensor and to every user it is shared with, whatever the role
+async fn can_read(client: &Client, user_id: &str, uuid: &str) -> Result<bool, Error> {
+    let device = client
+        .get_item()
+        .table_name(DEVICE_TABLE)
+        .key("user_id", AttributeValue::S(user_id.to_string()))
+        .key("device_id", AttributeValue::S(uuid.to_string()))
+        .send()
+        .await?;
+    if device.item().is_some() {
+        return Ok(true);
+    }
+
+    let grant = client
+        .get_item()
+        .table_name(DEVICE_GRANTS_TABLE)
+        .key("device_id", AttributeValue::S(uuid.to_string()))
+        .key("user_id", AttributeValue::S(user_id.to_string()))
         .send()
-        .await
-        .unwrap()
+        .await?;
+    Ok(grant.item().is_some())
 }
 
 fn hashmap_to_lists(measurements: Vec<HashMap<String, AttributeValue>>) -> Vec<SensorData> {
@@ -49,18 +98,20 @@ fn hashmap_to_lists(measurements: Vec<HashMap<String, AttributeValue>>) -> Vec<S
                 .unwrap()
                 .to_string(),
             media_month: *measurement.get("media_month").unwrap().as_bool().unwrap(),
-            soil_humidity: measurement
-                .get("soil_humidity")
-                .unwrap()
-                .as_n()
-                .unwrap()
-                .to_string(),
             temperature: measurement
                 .get("temperature")
                 .unwrap()
                 .as_n()
                 .unwrap()
                 .to_string(),
+            soil_humidity: optional_number(&measurement, "soil_humidity"),
+            light: optional_number(&measurement, "light"),
+            battery: optional_number(&measurement, "battery"),
+            rssi: optional_number(&measurement, "rssi"),
+            firmware_version: measurement
+                .get("firmware_version")
+                .and_then(|v| v.as_s().ok())
+                .map(|v| v.to_string()),
         })
     }
     list_of_measurements
@@ -75,7 +126,16 @@ async fn function_handler(event: Request) -> Result<Response<Body>, Error> {
         .and_then(|params| params.first("uuid"))
         .unwrap();
 
-    let measuration = hashmap_to_lists(get_list(&client, &uuid).await.items().unwrap().to_vec());
+    if !can_read(&client, &get_user_id(&event), uuid).await? {
+        let resp = Response::builder()
+            .status(403)
+            .header("content-type", "text/html")
+            .body("Sensor not shared with this user".into())
+            .map_err(Box::new)?;
+        return Ok(resp);
+    }
+
+    let measuration = hashmap_to_lists(get_list(&client, uuid).await?);
 
     let resp = Response::builder()
         .status(200)
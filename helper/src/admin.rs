@@ -0,0 +1,9 @@
+use std::env;
+
+/// Admins are the Firebase user_ids listed, comma separated, in the ADMIN_USER_IDS environment variable
+pub fn is_admin(user_id: &str) -> bool {
+    env::var("ADMIN_USER_IDS")
+        .unwrap_or_default()
+        .split(',')
+        .any(|admin| !admin.trim().is_empty() && admin.trim() == user_id)
+}
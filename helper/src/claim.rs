@@ -0,0 +1,25 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::env;
+
+/// Table of the devices registered at manufacturing, keyed by device_id
+pub const DEVICE_REGISTRY_TABLE: &str = "device_registry";
+/// Attribute of the registry with the keyed hash of the claim code
+pub const CLAIM_CODE_HMAC_ATTRIBUTE: &str = "claim_code_hmac";
+/// Failed claims in an attempt window after which the user or the device must wait for the next window
+pub const MAX_FAILED_CLAIMS: i64 = 5;
+
+/// Dashes are only for readability, K7Q2-9XPA and k7q29xpa are the same code
+pub fn normalize_claim_code(claim_code: &str) -> String {
+    claim_code.trim().to_uppercase().chars().filter(|c| *c != '-').collect()
+}
+
+/// Claim codes are printed on the box of the ESP8266, only their HMAC is saved in the registry.
+/// The key is the CLAIM_CODE_KEY environment variable of device_api and device_import, so a leaked
+/// registry can't be brute forced offline
+pub fn hash_claim_code(claim_code: &str) -> String {
+    let key = env::var("CLAIM_CODE_KEY").expect("CLAIM_CODE_KEY is not set");
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).unwrap();  // HMAC accepts keys of any length
+    mac.update(normalize_claim_code(claim_code).as_bytes());
+    mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,73 @@
+use serde::{Deserialize, Serialize};
+
+/// Commands sent to the devices, keyed by device_id + command_id
+pub const DEVICE_COMMANDS_TABLE: &str = "device_commands";
+/// Longest run of the pump, the ESP8266 would keep watering while unreachable
+pub const MAX_PUMP_SECONDS: u32 = 300;
+/// Seconds without acknowledgment after which a command is considered lost
+pub const COMMAND_TIMEOUT: i64 = 10 * 60;
+
+/// Topic the ESP8266 subscribes to, acknowledgments are published on the same topic + /ack
+pub fn command_topic(device_id: &str) -> String {
+    format!("sensor/commands/{}", device_id)
+}
+
+/*
+    Sent by the app and published to the device with its command_id:
+
+    {
+        "command_id": "1697712000123",
+        "type": "run_pump",
+        "seconds": 30
+    }
+*/
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+    Reboot,
+    SyncConfig,                 // Read again the plant config retained on sensor/plants/{sensor_id}
+    SetLight { on: bool },
+    RunPump { seconds: u32 },
+}
+
+impl Command {
+    pub fn is_valid(&self) -> bool {
+        match self {
+            Command::RunPump { seconds } => (1..=MAX_PUMP_SECONDS).contains(seconds),
+            _ => true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandStatus {
+    Pending,    // Saved, not published yet
+    Sent,       // Published, waiting for the device
+    Acked,      // Executed by the device
+    Failed,     // Not published or refused by the device
+    Expired,    // No acknowledgment within COMMAND_TIMEOUT, never saved in the table
+}
+
+impl CommandStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CommandStatus::Pending => "pending",
+            CommandStatus::Sent => "sent",
+            CommandStatus::Acked => "acked",
+            CommandStatus::Failed => "failed",
+            CommandStatus::Expired => "expired",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "pending" => Some(CommandStatus::Pending),
+            "sent" => Some(CommandStatus::Sent),
+            "acked" => Some(CommandStatus::Acked),
+            "failed" => Some(CommandStatus::Failed),
+            "expired" => Some(CommandStatus::Expired),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use serde::{Deserialize, Serialize};
+
+/*
+    Sent to notification_sender by mqtt_month_media_processor and offline_detector_lambda
+    when a sensor changes connectivity:
+
+    {
+        "user_id": "WLk7Giku6TYBMI22wfmTSJbWOVA2",
+        "uuid": "297a0620-3b4d-40ed-b407-2216eb0d",
+        "online": false,
+        "last_seen": 1696834199,
+        "device_name": "Pomodori"
+    }
+*/
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ConnectivityEvent {
+    pub user_id: String,    // Owner of the sensor
+    pub uuid: String,
+    pub online: bool,
+    pub last_seen: i64,
+    pub device_name: Option<String>,
+}
+
+/// Name of the notification_sender lambda, can be changed with the NOTIFICATION_SENDER_FUNCTION environment variable
+pub fn notification_sender_function() -> String {
+    std::env::var("NOTIFICATION_SENDER_FUNCTION").unwrap_or("notification_sender".to_string())
+}
+
+/// Invoke notification_sender without waiting for the push to be delivered, errors are only logged.
+/// Needs the notify feature, only the lambdas that send the event depend on aws-sdk-lambda
+#[cfg(feature = "notify")]
+pub async fn notify_connectivity(lambda_client: &aws_sdk_lambda::Client, event: &ConnectivityEvent) {
+    use aws_sdk_lambda::primitives::Blob;
+    use aws_sdk_lambda::types::InvocationType;
+
+    let result = lambda_client
+        .invoke()
+        .function_name(notification_sender_function())
+        .invocation_type(InvocationType::Event)
+        .payload(Blob::new(serde_json::to_string(event).unwrap()))
+        .send()
+        .await;
+
+    if let Err(err) = result {
+        println!("{:?}", err);
+    }
+}
@@ -0,0 +1,27 @@
+use sha2::{Digest, Sha256};
+
+/// Headers sent by the ESP8266 on the device endpoints, instead of the Firebase JWT
+pub const DEVICE_ID_HEADER: &str = "x-device-id";
+pub const DEVICE_SECRET_HEADER: &str = "x-device-secret";
+/// Attribute of the registry with the hash of the secret flashed at manufacturing
+pub const SECRET_HASH_ATTRIBUTE: &str = "device_secret_hash";
+/// Secrets imported by device_import or set by the admins must be at least this long, generated ones are 32 hex characters
+pub const MIN_SECRET_LENGTH: usize = 16;
+/// Invalid credentials of a device in an attempt window after which device_config_api refuses it until the next window
+pub const MAX_FAILED_CREDENTIALS: i64 = 10;
+
+pub fn hash_device_secret(secret: &str) -> String {
+    let digest = Sha256::digest(secret.as_bytes());
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Compare the whole hash every time, so the response time doesn't tell how many characters matched
+pub fn verify_device_secret(secret: &str, secret_hash: &str) -> bool {
+    let hash = hash_device_secret(secret);
+    hash.len() == secret_hash.len()
+        && hash
+            .bytes()
+            .zip(secret_hash.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
@@ -0,0 +1,77 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Firmware images uploaded by the admins, keyed by hardware_model + version
+pub const FIRMWARE_RELEASES_TABLE: &str = "firmware_releases";
+/// Rollouts of a release to the devices of its hardware model, keyed by rollout_id
+pub const FIRMWARE_ROLLOUTS_TABLE: &str = "firmware_rollouts";
+/// State of the rollout on every device, keyed by rollout_id + device_id
+pub const FIRMWARE_UPDATES_TABLE: &str = "firmware_updates";
+/// Lock of the active rollout of every hardware model, keyed by hardware_model with the rollout_id holding it.
+/// Written in the same transaction that activates or halts the rollout
+pub const FIRMWARE_ACTIVE_ROLLOUTS_TABLE: &str = "firmware_active_rollouts";
+
+/// Topic the ESP8266 subscribes to for updates, reports are published on the same topic + /report
+pub fn update_topic(device_id: &str) -> String {
+    format!("sensor/firmware/{}", device_id)
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RolloutStatus {
+    Active,     // New devices get the update when the percentage grows
+    Halted,     // Stopped by an admin or by too many failures
+}
+
+impl RolloutStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RolloutStatus::Active => "active",
+            RolloutStatus::Halted => "halted",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "active" => Some(RolloutStatus::Active),
+            "halted" => Some(RolloutStatus::Halted),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateStatus {
+    Sent,           // Update command published, the device may be offline
+    Downloading,
+    Installed,
+    Failed,
+}
+
+impl UpdateStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UpdateStatus::Sent => "sent",
+            UpdateStatus::Downloading => "downloading",
+            UpdateStatus::Installed => "installed",
+            UpdateStatus::Failed => "failed",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "sent" => Some(UpdateStatus::Sent),
+            "downloading" => Some(UpdateStatus::Downloading),
+            "installed" => Some(UpdateStatus::Installed),
+            "failed" => Some(UpdateStatus::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// Stable bucket from 0 to 99 of the device in the rollout, a device stays included when the percentage grows
+pub fn rollout_bucket(rollout_id: &str, device_id: &str) -> u32 {
+    let digest = Sha256::digest(format!("{}#{}", rollout_id, device_id).as_bytes());
+    u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) % 100
+}
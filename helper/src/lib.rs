@@ -1,3 +1,13 @@
+pub mod admin;
+pub mod claim;
+pub mod command;
+pub mod connectivity;
+pub mod device_auth;
+pub mod firmware;
+pub mod retention;
+pub mod sharing;
+pub mod throttle;
+
 use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation, TokenData};
 use lambda_http::Request;
 use serde::{Deserialize, Serialize};
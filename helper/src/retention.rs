@@ -0,0 +1,113 @@
+use std::env;
+
+/// Attribute used by DynamoDB TTL to delete expired rows
+pub const TTL_ATTRIBUTE: &str = "expires_at";
+
+/// Plan of every user, users without a row are on the free plan
+pub const USER_PLANS_TABLE: &str = "user_plans";
+
+const DAY: i64 = 24 * 60 * 60;
+
+/// What kind of row we are writing, every kind has its own retention
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataKind {
+    Raw,        // Every reading sent by the ESP8266
+    Hourly,     // Readings flagged with hour = true, hourly aggregates of stream_aggregation_lambda
+    Daily,      // Daily rollups of create_history_lambda and daily aggregates of stream_aggregation_lambda
+    Monthly,    // Monthly and yearly rollups of create_history_lambda
+}
+
+/// Days to keep every kind of row, None means forever
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetentionPolicy {
+    pub raw_days: Option<i64>,
+    pub hourly_days: Option<i64>,
+    pub daily_days: Option<i64>,
+    pub monthly_days: Option<i64>,
+}
+
+/// Read days from the lambda environment, 0 means forever
+fn env_days(name: &str, default: Option<i64>) -> Option<i64> {
+    match env::var(name).ok().and_then(|v| v.parse::<i64>().ok()) {
+        Some(0) => None,
+        Some(days) => Some(days),
+        None => default,
+    }
+}
+
+/// How many times the deployment default a plan keeps, can be changed with {PLAN}_RETENTION_MULTIPLIER
+/// environment variables, e.g. PRO_RETENTION_MULTIPLIER. The pro plan keeps three times the data by default
+fn plan_multiplier(plan: &str) -> i64 {
+    let default = if plan == "pro" { 3 } else { 1 };
+    env::var(format!("{}_RETENTION_MULTIPLIER", plan.to_uppercase()))
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .filter(|multiplier| *multiplier > 0)
+        .unwrap_or(default)
+}
+
+impl RetentionPolicy {
+    /// Policy of the deployment, can be changed with RAW_RETENTION_DAYS, HOURLY_RETENTION_DAYS,
+    /// DAILY_RETENTION_DAYS and MONTHLY_RETENTION_DAYS environment variables
+    pub fn from_env() -> Self {
+        RetentionPolicy {
+            raw_days: env_days("RAW_RETENTION_DAYS", Some(30)),
+            hourly_days: env_days("HOURLY_RETENTION_DAYS", Some(365)),
+            daily_days: env_days("DAILY_RETENTION_DAYS", Some(2 * 365)),
+            monthly_days: env_days("MONTHLY_RETENTION_DAYS", None),
+        }
+    }
+
+    /// Policy of a user plan, the days of the deployment default times the multiplier of the plan
+    pub fn for_plan(plan: Option<&str>) -> Self {
+        let default = RetentionPolicy::from_env();
+        let multiplier = match plan {
+            Some(plan) => plan_multiplier(plan),
+            None => 1,
+        };
+        RetentionPolicy {
+            raw_days: default.raw_days.map(|days| days * multiplier),
+            hourly_days: default.hourly_days.map(|days| days * multiplier),
+            daily_days: default.daily_days.map(|days| days * multiplier),
+            monthly_days: default.monthly_days.map(|days| days * multiplier),
+        }
+    }
+
+    /// Unix timestamp after which the row can be deleted, None if it is kept forever
+    pub fn expires_at(&self, kind: DataKind, timestamp: i64) -> Option<i64> {
+        let days = match kind {
+            DataKind::Raw => self.raw_days,
+            DataKind::Hourly => self.hourly_days,
+            DataKind::Daily => self.daily_days,
+            DataKind::Monthly => self.monthly_days,
+        };
+        days.map(|days| timestamp + days * DAY)
+    }
+}
+
+/// Retention policy of the plan of the user, deployment default if the plan can't be read.
+/// Needs the plans feature, only the lambdas that write expiring rows depend on aws-sdk-dynamodb
+#[cfg(feature = "plans")]
+pub async fn get_plan_policy(client: &aws_sdk_dynamodb::Client, user_id: &str) -> RetentionPolicy {
+    use aws_sdk_dynamodb::types::AttributeValue;
+
+    let result = client
+        .get_item()
+        .table_name(USER_PLANS_TABLE)
+        .key("user_id", AttributeValue::S(user_id.to_string()))
+        .send()
+        .await;
+
+    let plan = match result {
+        Ok(output) => output
+            .item()
+            .and_then(|item| item.get("plan"))
+            .and_then(|v| v.as_s().ok())
+            .map(|v| v.to_string()),
+        Err(err) => {
+            println!("{:?}", err);
+            None
+        }
+    };
+    RetentionPolicy::for_plan(plan.as_deref())
+}
@@ -0,0 +1,54 @@
+use serde::{Deserialize, Serialize};
+
+/// Users a device is shared with, keyed by device_id + user_id.
+/// Every grant also keeps owner_id, the user_id of the rows in devices and plants
+pub const DEVICE_GRANTS_TABLE: &str = "device_grants";
+/// GSI on user_id to list the devices shared with a user
+pub const USER_GRANTS_INDEX: &str = "user_id-index";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Viewer,     // Sees readings and plant, receives alerts
+    Editor,     // Also changes the plant and the device details
+    Owner,      // Also shares the device with other users, only the user who claimed it
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Editor => "editor",
+            Role::Owner => "owner",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "viewer" => Some(Role::Viewer),
+            "editor" => Some(Role::Editor),
+            "owner" => Some(Role::Owner),
+            _ => None,
+        }
+    }
+
+    /// Roles that can be given to another user, ownership only changes with a claim
+    pub fn can_be_granted(&self) -> bool {
+        *self != Role::Owner
+    }
+
+    pub fn can_edit(&self) -> bool {
+        *self != Role::Viewer
+    }
+
+    pub fn can_share(&self) -> bool {
+        *self == Role::Owner
+    }
+}
+
+/// Access of a user to a device
+#[derive(Clone, Debug, PartialEq)]
+pub struct Access {
+    pub role: Role,
+    pub owner_id: String,   // The user who claimed the device, rows of the device are saved with this user_id
+}
@@ -0,0 +1,15 @@
+/// Failed attempts of claims and device credentials, keyed by attempt_key.
+/// Every row counts one window and is deleted by TTL after it
+pub const FAILED_ATTEMPTS_TABLE: &str = "failed_attempts";
+/// Seconds of an attempt window, the counters start again from 0 in the next one
+pub const ATTEMPT_WINDOW: i64 = 15 * 60;
+
+/// Key of the counter of the current window, e.g. claim_user#WLk7Giku6TYBMI22wfmTSJbWOVA2#1886349
+pub fn attempt_key(scope: &str, id: &str, now: i64) -> String {
+    format!("{}#{}#{}", scope, id, now / ATTEMPT_WINDOW)
+}
+
+/// When the counter of the current window can be deleted
+pub fn attempt_expires_at(now: i64) -> i64 {
+    (now / ATTEMPT_WINDOW + 2) * ATTEMPT_WINDOW
+}
@@ -0,0 +1,67 @@
+use crate::owner::{forget_owner, get_owner, DEVICE_TABLE};
+use aws_sdk_dynamodb::types::{AttributeValue, ReturnValue};
+use aws_sdk_dynamodb::Client;
+use helper::connectivity::{notify_connectivity, ConnectivityEvent};
+
+/// Save when the sensor was last heard and bring it back online.
+/// Retired devices and devices being deleted are left alone, old readings uploaded late never move last_seen back
+pub async fn touch(
+    client: &Client,
+    lambda_client: &aws_sdk_lambda::Client,
+    uuid: &str,
+    last_seen: i64,
+    firmware_version: Option<&str>,
+) {
+    let owner = match get_owner(client, uuid).await {
+        Some(owner) => owner,
+        None => return,     // Not claimed yet, there is no registry row to update
+    };
+
+    let mut update_expression = "SET last_seen = :last_seen, #status = :active".to_string();
+    let mut request = client
+        .update_item()
+        .table_name(DEVICE_TABLE)
+        .key("user_id", AttributeValue::S(owner.clone()))
+        .key("device_id", AttributeValue::S(uuid.to_string()))
+        .condition_expression(
+            "attribute_exists(device_id) \
+             AND (attribute_not_exists(#status) OR (#status <> :retired AND #status <> :deleting)) \
+             AND (attribute_not_exists(last_seen) OR last_seen < :last_seen)",
+        )
+        .expression_attribute_names("#status", "status")
+        .expression_attribute_values(":last_seen", AttributeValue::N(last_seen.to_string()))
+        .expression_attribute_values(":active", AttributeValue::S("active".to_string()))
+        .expression_attribute_values(":retired", AttributeValue::S("retired".to_string()))
+        .expression_attribute_values(":deleting", AttributeValue::S("deleting".to_string()))
+        .return_values(ReturnValue::AllOld);
+    if let Some(firmware_version) = firmware_version {
+        update_expression.push_str(", firmware_version = :firmware_version");
+        request = request.expression_attribute_values(":firmware_version", AttributeValue::S(firmware_version.to_string()));
+    }
+
+    let result = request.update_expression(update_expression).send().await;
+    let old = match result {
+        Ok(output) => output.attributes().cloned().unwrap_or_default(),
+        Err(err) => {
+            if err.as_service_error().map(|e| e.is_conditional_check_failed_exception()) == Some(true) {
+                // Also fails when the device was released and claimed again, the cached owner may be stale
+                forget_owner(uuid);
+            } else {
+                println!("{:?}", err);
+            }
+            return;
+        }
+    };
+
+    let string = |key: &str| old.get(key).and_then(|v| v.as_s().ok()).map(|v| v.to_string());
+    if string("status").as_deref() == Some("offline") {
+        let event = ConnectivityEvent {
+            user_id: owner,
+            uuid: uuid.to_string(),
+            online: true,
+            last_seen,
+            device_name: string("name"),
+        };
+        notify_connectivity(lambda_client, &event).await;
+    }
+}
@@ -1,19 +1,29 @@
+mod heartbeat;
+mod owner;
+mod reading;
+mod retention;
+mod validation;
+
 use lambda_runtime::{run, service_fn, Error, LambdaEvent};
 use aws_sdk_dynamodb::Client;
-use aws_sdk_dynamodb::types::{ AttributeDefinition, ScalarAttributeType, KeySchemaElement, KeyType, BillingMode, AttributeValue};
-use serde::{Deserialize, Serialize};
+use aws_sdk_dynamodb::types::{ AttributeDefinition, ScalarAttributeType, KeySchemaElement, KeyType, BillingMode, AttributeValue, TableStatus, PutRequest, WriteRequest, TimeToLiveSpecification, StreamSpecification, StreamViewType};
+use helper::retention::{DataKind, TTL_ATTRIBUTE};
+use reading::IngestRequest;
+use serde::Serialize;
+use std::collections::HashMap;
 use chrono::Utc;
+use std::sync::Mutex;
+use std::time::Duration;
 
 // const TRACKER: &str = "TRACKER";
+const TELEMETRY_TABLE: &str = "sensor_telemetry"; // One table for every sensor, keyed by uuid + timestamp
+const TABLE_ACTIVE_RETRIES: u32 = 30;   // A new table usually becomes ACTIVE in a few seconds
+const INSERT_RETRIES: u32 = 5;
+const BATCH_SIZE: usize = 25;   // BatchWriteItem limit
+const MAX_CLOCK_SKEW: i64 = 300;    // Seconds, device timestamps further in the future are not trusted
 
-#[derive(Deserialize)]
-struct Request {
-    temperature: i8,
-    humidity: i8,
-    uuid: String,
-    hour: bool,
-    media_month: bool
-}
+// Tables already known to be ACTIVE, kept between warm invocations of the lambda
+static KNOWN_TABLES: Mutex<Vec<String>> = Mutex::new(Vec::new());
 
 #[derive(Serialize)]
 struct Response {
@@ -21,31 +31,104 @@ struct Response {
     msg: String,
 }
 
-async fn table_exists(client: &Client, table_name: String) -> bool {
-    let req = client.list_tables().limit(10);
-    let resp = req.send().await.unwrap();
-    resp.table_names().unwrap().contains(&table_name)
+/// Status of the table, None if it doesn't exist
+async fn table_status(client: &Client, table_name: &str) -> Result<Option<TableStatus>, Error> {
+    let response = client.describe_table().table_name(table_name).send().await;
+    match response {
+        Ok(output) => Ok(output.table().and_then(|table| table.table_status()).cloned()),
+        Err(err) => {
+            if err.as_service_error().map(|e| e.is_resource_not_found_exception()) == Some(true) {
+                Ok(None)
+            } else {
+                Err(err.into())
+            }
+        }
+    }
 }
 
-async fn create_table(client: &Client, table_name: &str, key: &str) -> bool {
-    let key_name: String = key.into();
+async fn wait_table_active(client: &Client, table_name: &str) -> Result<(), Error> {
+    for _ in 0..TABLE_ACTIVE_RETRIES {
+        if table_status(client, table_name).await? == Some(TableStatus::Active) {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    Err(format!("Table {} is not ACTIVE", table_name).into())
+}
 
-    let ad = AttributeDefinition::builder()
-    .attribute_name(key_name.clone())
-    .attribute_type(ScalarAttributeType::N)
-    .build();
+/// Create the table if needed and wait until we can write in it.
+/// Stream and TTL of tables created before are turned on by build.sh, not on every cold start
+async fn ensure_table(client: &Client, table_name: &str) -> Result<(), Error> {
+    if KNOWN_TABLES.lock().unwrap().iter().any(|table| table == table_name) {
+        return Ok(());
+    }
 
-    let ks = KeySchemaElement::builder()
-        .attribute_name(key_name)
-        .key_type(KeyType::Hash)
+    // If another invocation creates it at the same time create_table fails, we just wait for it
+    let created = table_status(client, table_name).await?.is_none() && create_table(client, table_name).await;
+    wait_table_active(client, table_name).await?;
+    if created {
+        // Only the invocation that created the table, readings are saved even if this fails
+        if let Err(err) = enable_ttl(client, table_name).await {
+            println!("{:?}", err);
+        }
+    }
+
+    KNOWN_TABLES.lock().unwrap().push(table_name.to_string());
+    Ok(())
+}
+
+/// New readings are streamed to stream_aggregation_lambda
+fn stream_specification() -> StreamSpecification {
+    StreamSpecification::builder()
+        .stream_enabled(true)
+        .stream_view_type(StreamViewType::NewImage)
+        .build()
+}
+
+/// Let DynamoDB delete the rows older than the retention policy
+async fn enable_ttl(client: &Client, table_name: &str) -> Result<(), Error> {
+    let specification = TimeToLiveSpecification::builder()
+        .enabled(true)
+        .attribute_name(TTL_ATTRIBUTE)
         .build();
+    client
+        .update_time_to_live()
+        .table_name(table_name)
+        .time_to_live_specification(specification)
+        .send()
+        .await?;
+    Ok(())
+}
+
+async fn create_table(client: &Client, table_name: &str) -> bool {
+    let attribute_definitions = vec![
+        AttributeDefinition::builder()
+            .attribute_name("uuid")
+            .attribute_type(ScalarAttributeType::S)
+            .build(),
+        AttributeDefinition::builder()
+            .attribute_name("timestamp")
+            .attribute_type(ScalarAttributeType::N)
+            .build(),
+    ];
+
+    let key_schema = vec![
+        KeySchemaElement::builder()
+            .attribute_name("uuid")
+            .key_type(KeyType::Hash)
+            .build(),
+        KeySchemaElement::builder()
+            .attribute_name("timestamp")
+            .key_type(KeyType::Range)
+            .build(),
+    ];
 
-    
     let req = client.create_table()
     .billing_mode(BillingMode::PayPerRequest)
     .table_name(table_name)
-    .key_schema(ks)
-    .attribute_definitions(ad)
+    .set_key_schema(Some(key_schema))
+    .set_attribute_definitions(Some(attribute_definitions))
+    .stream_specification(stream_specification())
     .send()
     .await;
 
@@ -62,29 +145,10 @@ async fn create_table(client: &Client, table_name: &str, key: &str) -> bool {
 
 }
 
-async fn insert_into(client: Client, 
-                table_name: String, 
-                key: &str, 
-                value: i8,
-                key2: &str,
-                value2: i8, 
-                key3: &str, 
-                value3: bool,
-                key4: &str,
-                value4: bool
-    ) -> bool {
-    let temp = AttributeValue::N(value.to_string());
-    let hum = AttributeValue::N(value2.to_string());
-    let dt = Utc::now();
-    let timestamp: i64 = dt.timestamp();
-    let timestamp = AttributeValue::N(timestamp.to_string());
+async fn insert_into(client: &Client, table_name: &str, item: HashMap<String, AttributeValue>) -> bool {
     let request = client.put_item()
     .table_name(table_name)
-    .item(key, temp)
-    .item(key2, hum)
-    .item("timestamp", timestamp)
-    .item(key3, AttributeValue::S(value3.to_string()))
-    .item(key4, AttributeValue::S(value4.to_string()));
+    .set_item(Some(item));
 
     let response = request.send().await;
     match response {
@@ -96,31 +160,133 @@ async fn insert_into(client: Client,
     }
 }
 
-async fn function_handler(event: LambdaEvent<Request>) -> Result<Response, Error> {
+/// Write up to BATCH_SIZE items, retrying the unprocessed ones
+async fn insert_batch(client: &Client, table_name: &str, items: Vec<HashMap<String, AttributeValue>>) -> bool {
+    let mut requests: Vec<WriteRequest> = items
+        .into_iter()
+        .map(|item| {
+            WriteRequest::builder()
+                .put_request(PutRequest::builder().set_item(Some(item)).build())
+                .build()
+        })
+        .collect();
+
+    for retry in 0..INSERT_RETRIES {
+        let response = client
+            .batch_write_item()
+            .request_items(table_name, requests.clone())
+            .send()
+            .await;
+        match response {
+            Ok(output) => {
+                requests = output
+                    .unprocessed_items()
+                    .and_then(|unprocessed| unprocessed.get(table_name))
+                    .cloned()
+                    .unwrap_or_default();
+                if requests.is_empty() {
+                    return true;
+                }
+            }
+            Err(err) => println!("{:?}", err),
+        }
+        tokio::time::sleep(Duration::from_millis(100 * 2u64.pow(retry))).await;
+    }
+    false
+}
+
+async fn function_handler(event: LambdaEvent<IngestRequest>) -> Result<Response, Error> {
     let shared_config = aws_config::load_from_env().await;
     let client = Client::new(&shared_config);
-    let uuid: String = event.payload.uuid;
+    let lambda_client = aws_sdk_lambda::Client::new(&shared_config);
+    let uuid = event.payload.uuid().to_string();
+    let now = Utc::now().timestamp();
+
+    if uuid.is_empty() {
+        return Err("Missing uuid".into());
+    }
+    ensure_table(&client, TELEMETRY_TABLE).await?;
+
+    // Same timestamp on every retry, so we never write the reading twice.
+    // Readings are keyed by uuid + timestamp, so duplicates in a batch are written only once.
+    let mut items: HashMap<i64, HashMap<String, AttributeValue>> = HashMap::new();
+    let mut readings = event.payload.into_readings();
+    readings.sort_by_key(|reading| reading.timestamp.unwrap_or(now));
+    let mut window = validation::get_window(&client, TELEMETRY_TABLE, &uuid).await;
+    let retention_policy = retention::get_retention_policy(&client, &uuid).await;
+    let mut rejected = 0;
+    let mut last_seen: Option<(i64, Option<String>)> = None;
+    for reading in readings {
+        let timestamp = match reading.timestamp {
+            Some(timestamp) if timestamp <= now + MAX_CLOCK_SKEW => timestamp,
+            Some(timestamp) => {
+                println!("Skipping reading of {} from the future: {}", uuid, timestamp);
+                continue;
+            }
+            None => now,
+        };
+        // Also rejected readings prove the device is alive. Readings are sorted, the last one is the newest
+        last_seen = Some((timestamp, reading.firmware_version.clone()));
+        let mut item = reading.to_item(timestamp);
+        let kind = if reading.hour { DataKind::Hourly } else { DataKind::Raw };
+        if let Some(expires_at) = retention_policy.expires_at(kind, timestamp) {
+            item.insert(TTL_ATTRIBUTE.to_string(), AttributeValue::N(expires_at.to_string()));
+        }
+
+        if let Err(reason) = validation::check_ranges(&reading) {
+            // Sensor errors never reach the telemetry table and the history averages
+            ensure_table(&client, validation::QUARANTINE_TABLE).await?;
+            validation::quarantine(&client, item, &reason).await;
+            rejected += 1;
+            continue;
+        }
+        match window.outlier(&reading) {
+            Some(reason) => {
+                // Could be real (e.g. greenhouse opened), keep it but flagged so rollups can skip it
+                item.insert("outlier".to_string(), AttributeValue::Bool(true));
+                item.insert("outlier_reason".to_string(), AttributeValue::S(reason));
+            }
+            None => window.push(&reading),
+        }
+        items.insert(timestamp, item);
+    }
+    if rejected > 0 {
+        validation::increment_rejected(&client, &uuid, rejected, now).await;
+    }
+    if let Some((timestamp, firmware_version)) = last_seen {
+        heartbeat::touch(&client, &lambda_client, &uuid, timestamp, firmware_version.as_deref()).await;
+    }
 
-    if !table_exists(&client, uuid.clone()).await{
-        create_table(&client, &uuid, "timestamp").await;
+    let items: Vec<HashMap<String, AttributeValue>> = items.into_values().collect();
+    let count = items.len();
+
+    if count == 1 {
+        let item = items.into_iter().next().unwrap();
+        for retry in 0..INSERT_RETRIES {
+            if insert_into(&client, TELEMETRY_TABLE, item.clone()).await {
+                return Ok(Response {
+                    req_id: event.context.request_id,
+                    msg: format!("{}", true),
+                });
+            }
+            tokio::time::sleep(Duration::from_millis(100 * 2u64.pow(retry))).await;
+        }
+    } else {
+        let mut inserted = true;
+        for chunk in items.chunks(BATCH_SIZE) {
+            inserted &= insert_batch(&client, TELEMETRY_TABLE, chunk.to_vec()).await;
+        }
+        if inserted {
+            return Ok(Response {
+                req_id: event.context.request_id,
+                msg: format!("{} readings saved", count),
+            });
+        }
     }
-    
-    let temp = event.payload.temperature;
-    let humidity = event.payload.humidity;
-    let hour = event.payload.hour;
-    let media_month = event.payload.media_month;
-
-    let resp = Response {
-        req_id: event.context.request_id,
-        msg: format!("{}", insert_into(client,
-            uuid, 
-            "temperature", temp, 
-            "humidity", humidity,
-            "hour", hour,
-            "media_month", media_month).await),
-    };
-
-    Ok(resp)
+
+    // Fail the invocation so Lambda retries the event instead of losing the readings.
+    // Writes are idempotent, readings already saved are just overwritten.
+    Err(format!("Cannot save readings of {}", uuid).into())
 }
 
 #[tokio::main]
@@ -0,0 +1,56 @@
+use aws_sdk_dynamodb::{types::AttributeValue, Client};
+use chrono::Utc;
+use std::sync::Mutex;
+
+pub const DEVICE_TABLE: &str = "devices";
+const DEVICE_INDEX: &str = "device_id-index";  // GSI on device_id to find the owner of a sensor
+const OWNER_CACHE_SECONDS: i64 = 5 * 60;    // A released device can be claimed by another user
+
+// Owner of every sensor already seen with the time of the lookup, kept between warm invocations of the lambda
+static OWNERS: Mutex<Vec<(String, String, i64)>> = Mutex::new(Vec::new());
+
+/// user_id of the owner of the sensor, None if nobody claimed it
+pub async fn get_owner(client: &Client, uuid: &str) -> Option<String> {
+    let now = Utc::now().timestamp();
+    {
+        let mut owners = OWNERS.lock().unwrap();
+        owners.retain(|(_, _, cached_at)| now - cached_at < OWNER_CACHE_SECONDS);
+        if let Some((_, owner, _)) = owners.iter().find(|(device, _, _)| device == uuid) {
+            return Some(owner.clone());
+        }
+    }
+
+    let results = client
+        .query()
+        .table_name(DEVICE_TABLE)
+        .index_name(DEVICE_INDEX)
+        .key_condition_expression("#device_id = :device_id")
+        .expression_attribute_names("#device_id", "device_id")
+        .expression_attribute_values(":device_id", AttributeValue::S(uuid.to_string()))
+        .limit(1)
+        .send()
+        .await;
+
+    let owner = match results {
+        Ok(output) => output
+            .items()
+            .and_then(|items| items.first())
+            .and_then(|item| item.get("user_id"))
+            .and_then(|v| v.as_s().ok())
+            .map(|v| v.to_string()),
+        Err(err) => {
+            println!("{:?}", err);
+            None
+        }
+    };
+    // Unclaimed sensors are not cached, they can be claimed while the lambda is warm
+    if let Some(owner) = &owner {
+        OWNERS.lock().unwrap().push((uuid.to_string(), owner.clone(), now));
+    }
+    owner
+}
+
+/// Drop the cached owner, the next reading looks it up again
+pub fn forget_owner(uuid: &str) {
+    OWNERS.lock().unwrap().retain(|(device, _, _)| device != uuid);
+}
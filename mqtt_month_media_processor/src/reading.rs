@@ -0,0 +1,117 @@
+use aws_sdk_dynamodb::types::AttributeValue;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/*
+    Example MQTT message:
+
+    {
+        "uuid": "297a0620-3b4d-40ed-b407-2216eb0d",
+        "temperature": 24.5,
+        "humidity": 63.2,
+        "soil_humidity": 40,
+        "light": 812,
+        "battery": 3.9,
+        "rssi": -67,
+        "firmware_version": "1.2.0",
+        "hour": true,
+        "media_month": false
+    }
+
+    Only uuid, temperature and humidity are required, older firmwares don't send the other channels.
+
+    After a downtime the ESP8266 uploads the readings it buffered in a single message,
+    each one with the timestamp of when it was measured:
+
+    {
+        "uuid": "297a0620-3b4d-40ed-b407-2216eb0d",
+        "readings": [
+            { "timestamp": 1696834199, "temperature": 24.5, "humidity": 63.2 },
+            { "timestamp": 1696837799, "temperature": 23.9, "humidity": 64.0 }
+        ]
+    }
+*/
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum IngestRequest {
+    Batch(BatchRequest),
+    Single(Reading),
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct BatchRequest {
+    pub uuid: String,
+    pub readings: Vec<Reading>,
+}
+
+impl IngestRequest {
+    pub fn uuid(&self) -> &str {
+        match self {
+            IngestRequest::Batch(batch) => &batch.uuid,
+            IngestRequest::Single(reading) => &reading.uuid,
+        }
+    }
+
+    /// Every reading with its uuid filled, in batch mode it is only in the message
+    pub fn into_readings(self) -> Vec<Reading> {
+        match self {
+            IngestRequest::Batch(batch) => batch
+                .readings
+                .into_iter()
+                .map(|mut reading| {
+                    reading.uuid = batch.uuid.clone();
+                    reading
+                })
+                .collect(),
+            IngestRequest::Single(reading) => vec![reading],
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Reading {
+    #[serde(default)]   // Not repeated in every reading of a batch
+    pub uuid: String,
+    pub timestamp: Option<i64>,     // Set by the device for buffered readings
+    pub temperature: f32,   // °C
+    pub humidity: f32,      // %
+    pub soil_humidity: Option<f32>, // %
+    pub light: Option<f32>,         // Lux
+    pub battery: Option<f32>,       // Volt
+    pub rssi: Option<i32>,          // Wi-Fi signal, dBm
+    pub firmware_version: Option<String>,
+    #[serde(default)]
+    pub hour: bool,
+    #[serde(default)]
+    pub media_month: bool,
+}
+
+impl Reading {
+    /// DynamoDB item of the reading, missing channels are not saved
+    pub fn to_item(&self, timestamp: i64) -> HashMap<String, AttributeValue> {
+        let mut item = HashMap::new();
+        item.insert("uuid".to_string(), AttributeValue::S(self.uuid.clone()));
+        item.insert("timestamp".to_string(), AttributeValue::N(timestamp.to_string()));
+        item.insert("temperature".to_string(), AttributeValue::N(self.temperature.to_string()));
+        item.insert("humidity".to_string(), AttributeValue::N(self.humidity.to_string()));
+        item.insert("hour".to_string(), AttributeValue::Bool(self.hour));
+        item.insert("media_month".to_string(), AttributeValue::Bool(self.media_month));
+
+        let optional_channels = [
+            ("soil_humidity", self.soil_humidity),
+            ("light", self.light),
+            ("battery", self.battery),
+            ("rssi", self.rssi.map(|v| v as f32)),
+        ];
+        for (key, value) in optional_channels {
+            if let Some(value) = value {
+                item.insert(key.to_string(), AttributeValue::N(value.to_string()));
+            }
+        }
+        if let Some(firmware_version) = &self.firmware_version {
+            item.insert("firmware_version".to_string(), AttributeValue::S(firmware_version.clone()));
+        }
+        item
+    }
+}
@@ -0,0 +1,29 @@
+use crate::owner;
+use aws_sdk_dynamodb::Client;
+use chrono::Utc;
+use helper::retention::{get_plan_policy, RetentionPolicy};
+use std::sync::Mutex;
+
+const POLICY_CACHE_SECONDS: i64 = 5 * 60;   // The owner can change plan while the lambda is warm
+
+// Policy of every sensor already seen with the time of the lookup, kept between warm invocations of the lambda
+static POLICIES: Mutex<Vec<(String, RetentionPolicy, i64)>> = Mutex::new(Vec::new());
+
+/// Retention policy of the plan of the sensor owner, deployment default if unknown
+pub async fn get_retention_policy(client: &Client, uuid: &str) -> RetentionPolicy {
+    let now = Utc::now().timestamp();
+    {
+        let mut policies = POLICIES.lock().unwrap();
+        policies.retain(|(_, _, cached_at)| now - cached_at < POLICY_CACHE_SECONDS);
+        if let Some((_, policy, _)) = policies.iter().find(|(device, _, _)| device == uuid) {
+            return *policy;
+        }
+    }
+
+    let policy = match owner::get_owner(client, uuid).await {
+        Some(user_id) => get_plan_policy(client, &user_id).await,
+        None => RetentionPolicy::for_plan(None),
+    };
+    POLICIES.lock().unwrap().push((uuid.to_string(), policy, now));
+    policy
+}
@@ -0,0 +1,169 @@
+use crate::reading::Reading;
+use aws_sdk_dynamodb::{types::AttributeValue, Client};
+use std::collections::HashMap;
+
+pub const QUARANTINE_TABLE: &str = "sensor_quarantine"; // Rejected readings, same key as the telemetry table
+const DEVICE_STATS_TABLE: &str = "device_stats";    // Per device counters, read by device_api
+
+const OUTLIER_WINDOW: usize = 20;       // Last readings used to compute mean and standard deviation
+const WINDOW_MAX_PAGES: usize = 5;      // Bound the reads when most of the recent readings are outliers
+const OUTLIER_MIN_SAMPLES: usize = 5;   // Not enough history to say a reading is an outlier
+const OUTLIER_STD_DEVIATIONS: f32 = 4.0;
+const OUTLIER_MIN_STD: f32 = 0.5;       // A very stable greenhouse must not flag every small change
+
+/// Physical range of every channel, values outside are sensor errors (e.g. -127 from a disconnected probe)
+const TEMPERATURE_RANGE: (f32, f32) = (-40.0, 80.0);    // DHT22 range
+const HUMIDITY_RANGE: (f32, f32) = (0.0, 100.0);
+const SOIL_HUMIDITY_RANGE: (f32, f32) = (0.0, 100.0);
+const LIGHT_RANGE: (f32, f32) = (0.0, 200000.0);
+const BATTERY_RANGE: (f32, f32) = (2.0, 5.0);
+const RSSI_RANGE: (f32, f32) = (-120.0, 0.0);
+
+fn check_range(name: &str, value: f32, range: (f32, f32)) -> Result<(), String> {
+    if value.is_nan() || value < range.0 || value > range.1 {
+        Err(format!("{} out of range: {}", name, value))
+    } else {
+        Ok(())
+    }
+}
+
+/// Reject readings that can't be physically true
+pub fn check_ranges(reading: &Reading) -> Result<(), String> {
+    check_range("temperature", reading.temperature, TEMPERATURE_RANGE)?;
+    check_range("humidity", reading.humidity, HUMIDITY_RANGE)?;
+    if reading.humidity == 0.0 {
+        return Err("humidity is 0, sensor disconnected".to_string());
+    }
+    let optional_channels = [
+        ("soil_humidity", reading.soil_humidity, SOIL_HUMIDITY_RANGE),
+        ("light", reading.light, LIGHT_RANGE),
+        ("battery", reading.battery, BATTERY_RANGE),
+        ("rssi", reading.rssi.map(|v| v as f32), RSSI_RANGE),
+    ];
+    for (name, value, range) in optional_channels {
+        if let Some(value) = value {
+            check_range(name, value, range)?;
+        }
+    }
+    Ok(())
+}
+
+/// Recent valid readings of a device, oldest first
+pub struct Window {
+    temperature: Vec<f32>,
+    humidity: Vec<f32>,
+}
+
+fn mean_and_std(values: &[f32]) -> (f32, f32) {
+    let mean = values.iter().sum::<f32>() / values.len() as f32;
+    let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / values.len() as f32;
+    (mean, variance.sqrt())
+}
+
+fn is_outlier(values: &[f32], value: f32) -> bool {
+    if values.len() < OUTLIER_MIN_SAMPLES {
+        return false;
+    }
+    let (mean, std) = mean_and_std(values);
+    (value - mean).abs() > OUTLIER_STD_DEVIATIONS * std.max(OUTLIER_MIN_STD)
+}
+
+impl Window {
+    /// Reason why the reading is too far from the recent ones, None if it looks fine
+    pub fn outlier(&self, reading: &Reading) -> Option<String> {
+        if is_outlier(&self.temperature, reading.temperature) {
+            Some(format!("temperature jump: {}", reading.temperature))
+        } else if is_outlier(&self.humidity, reading.humidity) {
+            Some(format!("humidity jump: {}", reading.humidity))
+        } else {
+            None
+        }
+    }
+
+    /// Add a valid reading, the oldest one leaves the window
+    pub fn push(&mut self, reading: &Reading) {
+        for (values, value) in [(&mut self.temperature, reading.temperature), (&mut self.humidity, reading.humidity)] {
+            values.push(value);
+            if values.len() > OUTLIER_WINDOW {
+                values.remove(0);
+            }
+        }
+    }
+}
+
+fn attribute_to_f32(item: &HashMap<String, AttributeValue>, key: &str) -> Option<f32> {
+    item.get(key)
+        .and_then(|v| v.as_n().ok())
+        .and_then(|v| v.parse::<f32>().ok())
+}
+
+/// Last OUTLIER_WINDOW valid readings of the device.
+/// The filter is applied after the limit, so pages are read until there are enough readings that aren't outliers
+pub async fn get_window(client: &Client, table_name: &str, uuid: &str) -> Window {
+    let mut items: Vec<HashMap<String, AttributeValue>> = Vec::new();
+    let mut start_key = None;
+    for _ in 0..WINDOW_MAX_PAGES {
+        let results = client
+            .query()
+            .table_name(table_name)
+            .key_condition_expression("#uuid = :uuid")
+            .filter_expression("attribute_not_exists(outlier)")    // Outliers must not move the window
+            .expression_attribute_names("#uuid", "uuid")
+            .expression_attribute_values(":uuid", AttributeValue::S(uuid.to_string()))
+            .scan_index_forward(false)  // Newest first
+            .limit(OUTLIER_WINDOW as i32)
+            .set_exclusive_start_key(start_key)
+            .send()
+            .await;
+
+        let output = match results {
+            Ok(output) => output,
+            Err(err) => {
+                println!("{:?}", err);
+                break;  // Without history we can still check the ranges
+            }
+        };
+        items.extend(output.items.unwrap_or_default());
+        start_key = output.last_evaluated_key;
+        if items.len() >= OUTLIER_WINDOW || start_key.is_none() {
+            break;
+        }
+    }
+    items.truncate(OUTLIER_WINDOW);
+    items.reverse();
+
+    Window {
+        temperature: items.iter().filter_map(|item| attribute_to_f32(item, "temperature")).collect(),
+        humidity: items.iter().filter_map(|item| attribute_to_f32(item, "humidity")).collect(),
+    }
+}
+
+pub async fn quarantine(client: &Client, mut item: HashMap<String, AttributeValue>, reason: &str) {
+    item.insert("reason".to_string(), AttributeValue::S(reason.to_string()));
+    let result = client
+        .put_item()
+        .table_name(QUARANTINE_TABLE)
+        .set_item(Some(item))
+        .send()
+        .await;
+
+    if let Err(err) = result {
+        println!("{:?}", err);
+    }
+}
+
+pub async fn increment_rejected(client: &Client, uuid: &str, count: usize, timestamp: i64) {
+    let result = client
+        .update_item()
+        .table_name(DEVICE_STATS_TABLE)
+        .key("device_id", AttributeValue::S(uuid.to_string()))
+        .update_expression("ADD rejected_readings :count SET last_rejected = :timestamp")
+        .expression_attribute_values(":count", AttributeValue::N(count.to_string()))
+        .expression_attribute_values(":timestamp", AttributeValue::N(timestamp.to_string()))
+        .send()
+        .await;
+
+    if let Err(err) = result {
+        println!("{:?}", err);
+    }
+}
@@ -1,2 +1,3 @@
 #!/bin/bash
-cargo lambda invoke --data-ascii "{ \"temperature\": 1, \"humidity\": 10, \"uuid\": \"test\", \"hour\": true, \"media_month\": false }"
+cargo lambda invoke --data-file utils/test.json
+cargo lambda invoke --data-file utils/test_batch.json
@@ -0,0 +1,13 @@
+use lambda_http::{Request, Response, Body, Error};
+use aws_sdk_dynamodb::Client;
+mod endpoints;
+use self::endpoints::{get_notifications, mark_as_read, delete_notification, not_implemented};
+
+pub async fn router(request: Request, client: &Client) -> Result<Response<Body>, Error> {
+    match request.method().as_str() {
+        "GET" => get_notifications(request, client).await,
+        "PUT" => mark_as_read(request, client).await,
+        "DELETE" => delete_notification(request, client).await,
+        _ => not_implemented(),
+    }
+}
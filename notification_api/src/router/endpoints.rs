@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+
+use aws_sdk_dynamodb::{types::AttributeValue, Client};
+use lambda_http::{Body, Error, Request, RequestExt, Response};
+use serde::{Deserialize, Serialize};
+use helper::get_user_id;
+
+const HISTORY_TABLE_NAME: &str = "notification_history"; // Written by notification_sender
+const DEFAULT_PAGE_SIZE: i32 = 20;
+const MAX_PAGE_SIZE: i32 = 100;
+
+#[derive(Serialize, Deserialize)]
+struct ResponseBody<'a> {
+    error: bool,
+    message: &'a str,
+}
+
+#[derive(Serialize, Deserialize)]
+struct NotificationItem {
+    notification_id: String,
+    kind: String,
+    alert_type: String,
+    sensor_id: String,
+    title: String,
+    body: String,
+    payload: serde_json::Value, // Same data payload sent with the push notification
+    delivered: i64,
+    failed: i64,
+    read: bool,
+    timestamp: i64,
+}
+
+#[derive(Serialize)]
+struct Page {
+    items: Vec<NotificationItem>,
+    next: Option<String>,   // Pass it as "next" to get the following page
+}
+
+fn attribute_to_string(item: &HashMap<String, AttributeValue>, key: &str) -> String {
+    item.get(key).unwrap().as_s().unwrap().to_string()
+}
+
+fn attribute_to_i64(item: &HashMap<String, AttributeValue>, key: &str) -> i64 {
+    item.get(key).unwrap().as_n().unwrap().parse::<i64>().unwrap()
+}
+
+impl From<&HashMap<String, AttributeValue>> for NotificationItem {
+    fn from(item: &HashMap<String, AttributeValue>) -> Self {
+        NotificationItem {
+            notification_id: attribute_to_string(item, "notification_id"),
+            kind: attribute_to_string(item, "kind"),
+            alert_type: attribute_to_string(item, "alert_type"),
+            sensor_id: attribute_to_string(item, "sensor_id"),
+            title: attribute_to_string(item, "title"),
+            body: attribute_to_string(item, "body"),
+            payload: serde_json::from_str(&attribute_to_string(item, "payload")).unwrap_or_default(),
+            delivered: attribute_to_i64(item, "delivered"),
+            failed: attribute_to_i64(item, "failed"),
+            read: *item.get("read").unwrap().as_bool().unwrap(),
+            timestamp: attribute_to_i64(item, "timestamp"),
+        }
+    }
+}
+
+fn response(status: u16, error: bool, message: &str) -> Result<Response<Body>, Error> {
+    let response_body = ResponseBody { error, message };
+
+    let response = Response::builder()
+        .status(status)
+        .header("content-type", "text/html")
+        .body(serde_json::to_string(&response_body).unwrap().into())
+        .map_err(Box::new)?;
+    Ok(response)
+}
+
+fn get_notification_id(req: &Request) -> Option<String> {
+    req.query_string_parameters_ref()
+        .and_then(|params| params.first("notification_id"))
+        .map(|v| v.to_string())
+}
+
+pub async fn get_notifications(req: Request, client: &Client) -> Result<Response<Body>, Error> {
+    let user_id = get_user_id(&req);
+    let params = req.query_string_parameters_ref();
+
+    let limit = params
+        .and_then(|params| params.first("limit"))
+        .and_then(|v| v.parse::<i32>().ok())
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let next = params.and_then(|params| params.first("next"));
+    let only_unread = params.and_then(|params| params.first("unread")) == Some("true");
+
+    let mut request = client
+        .query()
+        .table_name(HISTORY_TABLE_NAME)
+        .key_condition_expression("#key = :value")
+        .expression_attribute_names("#key", "user_id")
+        .expression_attribute_values(":value", AttributeValue::S(user_id.clone()))
+        .scan_index_forward(false)  // Newest first
+        .limit(limit);
+
+    if let Some(next) = next {
+        request = request
+            .exclusive_start_key("user_id", AttributeValue::S(user_id))
+            .exclusive_start_key("notification_id", AttributeValue::S(next.to_string()));
+    }
+    if only_unread {
+        // Applied after limit, so a page can contain less than limit items
+        request = request
+            .filter_expression("#read = :read")
+            .expression_attribute_names("#read", "read")
+            .expression_attribute_values(":read", AttributeValue::Bool(false));
+    }
+
+    let results = request.send().await?;
+
+    let page = Page {
+        items: results
+            .items()
+            .unwrap_or_default()
+            .iter()
+            .map(|item| item.into())
+            .collect(),
+        next: results
+            .last_evaluated_key()
+            .and_then(|key| key.get("notification_id"))
+            .map(|v| v.as_s().unwrap().to_string()),
+    };
+
+    let response = Response::builder()
+        .status(200)
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&page).unwrap().into())
+        .map_err(Box::new)?;
+    Ok(response)
+}
+
+pub async fn mark_as_read(req: Request, client: &Client) -> Result<Response<Body>, Error> {
+    let user_id = get_user_id(&req);
+    let notification_id = match get_notification_id(&req) {
+        Some(notification_id) => notification_id,
+        None => return response(400, true, "Missing notification_id"),
+    };
+
+    let request = client
+        .update_item()
+        .table_name(HISTORY_TABLE_NAME)
+        .key("user_id", AttributeValue::S(user_id))
+        .key("notification_id", AttributeValue::S(notification_id))
+        .update_expression("SET #read = :read")
+        .condition_expression("attribute_exists(notification_id)")  // Don't create empty notifications
+        .expression_attribute_names("#read", "read")
+        .expression_attribute_values(":read", AttributeValue::Bool(true))
+        .send()
+        .await;
+
+    match request {
+        Ok(_out) => response(200, false, "Notification marked as read"),
+        Err(e) => {
+            if e.as_service_error().map(|v| v.is_conditional_check_failed_exception()) == Some(true) {
+                response(404, true, "Notification not found")
+            } else {
+                Err(e.into())
+            }
+        }
+    }
+}
+
+pub async fn delete_notification(req: Request, client: &Client) -> Result<Response<Body>, Error> {
+    let user_id = get_user_id(&req);
+    let notification_id = match get_notification_id(&req) {
+        Some(notification_id) => notification_id,
+        None => return response(400, true, "Missing notification_id"),
+    };
+
+    let request = client
+        .delete_item()
+        .table_name(HISTORY_TABLE_NAME)
+        .key("user_id", AttributeValue::S(user_id))
+        .key("notification_id", AttributeValue::S(notification_id))
+        .send()
+        .await;
+
+    match request {
+        Ok(_out) => response(200, false, "Notification deleted successfully"),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn not_implemented() -> Result<Response<Body>, Error> {
+    response(404, true, "API not implemented")
+}
@@ -0,0 +1,270 @@
+use aws_sdk_dynamodb::{types::AttributeValue, Client};
+use chrono::{DateTime, Duration, Timelike, Utc};
+use std::collections::HashMap;
+
+const ALERT_STATE_TABLE: &str = "notification_alert_state"; // One row for every (user, sensor, alert type)
+const SETTINGS_TABLE: &str = "notification_settings"; // Per user cooldown and quiet hours
+const DEFAULT_COOLDOWN_MINUTES: i64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AlertType {
+    Temperature,
+    Humidity,
+    SoilHumidity,
+}
+
+impl AlertType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AlertType::Temperature => "temperature",
+            AlertType::Humidity => "humidity",
+            AlertType::SoilHumidity => "soil_humidity",
+        }
+    }
+
+    fn from_str(value: &str) -> Option<AlertType> {
+        match value {
+            "temperature" => Some(AlertType::Temperature),
+            "humidity" => Some(AlertType::Humidity),
+            "soil_humidity" => Some(AlertType::SoilHumidity),
+            _ => None,
+        }
+    }
+}
+
+/// What we have to do with an alert after looking at its previous state
+#[derive(Debug, PartialEq)]
+pub enum Decision {
+    Send,       // Out of range and not in cooldown
+    Recovery,   // Back to normal after an alert
+    Suppress,   // Would be sent but we are in quiet hours, goes in the digest sent with the first reading after them
+    Skip,       // Nothing to do (in cooldown or still normal)
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub cooldown_minutes: i64,
+    pub quiet_hours_start: Option<u32>, // Hour of the day, in user local time
+    pub quiet_hours_end: Option<u32>,
+    pub utc_offset_minutes: i64,        // User timezone
+    pub digest_pending: bool,           // Some alerts were suppressed during quiet hours
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            cooldown_minutes: DEFAULT_COOLDOWN_MINUTES,
+            quiet_hours_start: None,
+            quiet_hours_end: None,
+            utc_offset_minutes: 0,
+            digest_pending: false,
+        }
+    }
+}
+
+impl Settings {
+    pub fn is_quiet(&self, now: DateTime<Utc>) -> bool {
+        let (start, end) = match (self.quiet_hours_start, self.quiet_hours_end) {
+            (Some(start), Some(end)) if start != end => (start, end),
+            _ => return false,
+        };
+        let hour = (now + Duration::minutes(self.utc_offset_minutes)).hour();
+        if start < end {
+            start <= hour && hour < end
+        } else {
+            hour >= start || hour < end // Quiet hours across midnight, e.g. 22 -> 7
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AlertState {
+    pub sensor_id: String,
+    pub alert_type: AlertType,
+    pub active: bool,       // Sensor is currently out of range
+    pub last_sent: i64,     // Timestamp of the last notification sent for this alert
+    pub suppressed: i64,    // Notifications not sent because of quiet hours
+}
+
+impl AlertState {
+    fn new(sensor_id: &str, alert_type: AlertType) -> Self {
+        AlertState {
+            sensor_id: sensor_id.to_string(),
+            alert_type,
+            active: false,
+            last_sent: 0,
+            suppressed: 0,
+        }
+    }
+
+    /// Update the state with the new reading and decide if a notification must be sent
+    pub fn evaluate(&mut self, firing: bool, now: DateTime<Utc>, settings: &Settings) -> Decision {
+        let quiet = settings.is_quiet(now);
+        let cooldown_expired = now.timestamp() - self.last_sent >= settings.cooldown_minutes * 60;
+
+        match (firing, self.active) {
+            (true, _) if !self.active || cooldown_expired => {
+                self.active = true;
+                self.last_sent = now.timestamp();
+                if quiet {
+                    self.suppressed += 1;
+                    Decision::Suppress
+                } else {
+                    Decision::Send
+                }
+            }
+            (false, true) => {
+                self.active = false;
+                if quiet {
+                    Decision::Skip // The digest already tells the user what happened
+                } else {
+                    Decision::Recovery
+                }
+            }
+            _ => Decision::Skip,
+        }
+    }
+}
+
+fn alert_key(sensor_id: &str, alert_type: AlertType) -> String {
+    format!("{}#{}", sensor_id, alert_type.as_str())
+}
+
+fn attribute_to_i64(item: &HashMap<String, AttributeValue>, key: &str) -> Option<i64> {
+    item.get(key)
+        .and_then(|v| v.as_n().ok())
+        .and_then(|v| v.parse::<i64>().ok())
+}
+
+impl From<&HashMap<String, AttributeValue>> for Settings {
+    fn from(item: &HashMap<String, AttributeValue>) -> Self {
+        let default = Settings::default();
+        Settings {
+            cooldown_minutes: attribute_to_i64(item, "cooldown_minutes").unwrap_or(default.cooldown_minutes),
+            quiet_hours_start: attribute_to_i64(item, "quiet_hours_start").map(|v| v as u32),
+            quiet_hours_end: attribute_to_i64(item, "quiet_hours_end").map(|v| v as u32),
+            utc_offset_minutes: attribute_to_i64(item, "utc_offset_minutes").unwrap_or(0),
+            digest_pending: item
+                .get("digest_pending")
+                .and_then(|v| v.as_bool().ok())
+                .copied()
+                .unwrap_or(false),
+        }
+    }
+}
+
+impl From<&HashMap<String, AttributeValue>> for AlertState {
+    fn from(item: &HashMap<String, AttributeValue>) -> Self {
+        AlertState {
+            sensor_id: item.get("sensor_id").unwrap().as_s().unwrap().to_string(),
+            alert_type: AlertType::from_str(item.get("alert_type").unwrap().as_s().unwrap())
+                .expect("Unknown alert type"),
+            active: *item.get("active").unwrap().as_bool().unwrap(),
+            last_sent: attribute_to_i64(item, "last_sent").unwrap_or(0),
+            suppressed: attribute_to_i64(item, "suppressed").unwrap_or(0),
+        }
+    }
+}
+
+pub async fn get_settings(client: &Client, user_id: &str) -> Settings {
+    let result = client
+        .get_item()
+        .table_name(SETTINGS_TABLE)
+        .key("user_id", AttributeValue::S(user_id.to_string()))
+        .send()
+        .await;
+
+    match result {
+        Ok(output) => output.item().map(|item| item.into()).unwrap_or_default(),
+        Err(err) => {
+            println!("{:?}", err);
+            Settings::default() // Never lose an alert because settings are not readable
+        }
+    }
+}
+
+pub async fn set_digest_pending(client: &Client, user_id: &str, pending: bool) {
+    let result = client
+        .update_item()
+        .table_name(SETTINGS_TABLE)
+        .key("user_id", AttributeValue::S(user_id.to_string()))
+        .update_expression("SET digest_pending = :pending")
+        .expression_attribute_values(":pending", AttributeValue::Bool(pending))
+        .send()
+        .await;
+
+    if let Err(err) = result {
+        println!("{:?}", err);
+    }
+}
+
+pub async fn get_alert_state(client: &Client, user_id: &str, sensor_id: &str, alert_type: AlertType) -> AlertState {
+    let result = client
+        .get_item()
+        .table_name(ALERT_STATE_TABLE)
+        .key("user_id", AttributeValue::S(user_id.to_string()))
+        .key("alert_key", AttributeValue::S(alert_key(sensor_id, alert_type)))
+        .send()
+        .await;
+
+    match result {
+        Ok(output) => output
+            .item()
+            .map(|item| item.into())
+            .unwrap_or_else(|| AlertState::new(sensor_id, alert_type)),
+        Err(err) => {
+            println!("{:?}", err);
+            AlertState::new(sensor_id, alert_type)
+        }
+    }
+}
+
+pub async fn save_alert_state(client: &Client, user_id: &str, state: &AlertState) {
+    let result = client
+        .put_item()
+        .table_name(ALERT_STATE_TABLE)
+        .item("user_id", AttributeValue::S(user_id.to_string()))
+        .item("alert_key", AttributeValue::S(alert_key(&state.sensor_id, state.alert_type)))
+        .item("sensor_id", AttributeValue::S(state.sensor_id.clone()))
+        .item("alert_type", AttributeValue::S(state.alert_type.as_str().to_string()))
+        .item("active", AttributeValue::Bool(state.active))
+        .item("last_sent", AttributeValue::N(state.last_sent.to_string()))
+        .item("suppressed", AttributeValue::N(state.suppressed.to_string()))
+        .send()
+        .await;
+
+    if let Err(err) = result {
+        println!("{:?}", err);
+    }
+}
+
+/// Return every alert suppressed during quiet hours and reset the counters
+pub async fn take_suppressed_alerts(client: &Client, user_id: &str) -> Vec<AlertState> {
+    let results = client
+        .query()
+        .table_name(ALERT_STATE_TABLE)
+        .key_condition_expression("#user_id = :uid")
+        .expression_attribute_names("#user_id", "user_id")
+        .expression_attribute_values(":uid", AttributeValue::S(user_id.to_string()))
+        .send()
+        .await;
+
+    let items = match results {
+        Ok(output) => output.items.unwrap_or_default(),
+        Err(err) => {
+            println!("{:?}", err);
+            return Vec::new();
+        }
+    };
+
+    let mut suppressed = Vec::new();
+    for item in items.iter() {
+        let mut state: AlertState = item.into();
+        if state.suppressed > 0 {
+            suppressed.push(state.clone());
+            state.suppressed = 0;
+            save_alert_state(client, user_id, &state).await;
+        }
+    }
+    suppressed
+}
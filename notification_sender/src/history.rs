@@ -0,0 +1,40 @@
+use crate::notification::PushNotification;
+use aws_sdk_dynamodb::{types::AttributeValue, Client};
+use chrono::Utc;
+
+const HISTORY_TABLE: &str = "notification_history"; // Read by notification_api for the in-app inbox
+
+/// Save a sent notification in the user inbox.
+/// notification_id starts with the timestamp so the inbox is sorted by date.
+pub async fn save_notification(client: &Client, user_id: &str, notification: &PushNotification, delivered: usize, failed: usize) {
+    let now = Utc::now();
+    let notification_id = format!(
+        "{:013}#{}#{}#{}",
+        now.timestamp_millis(),
+        notification.data.sensor_uuid,
+        notification.data.kind.as_str(),
+        notification.data.alert_type
+    );
+
+    let result = client
+        .put_item()
+        .table_name(HISTORY_TABLE)
+        .item("user_id", AttributeValue::S(user_id.to_string()))
+        .item("notification_id", AttributeValue::S(notification_id))
+        .item("kind", AttributeValue::S(notification.data.kind.as_str().to_string()))
+        .item("alert_type", AttributeValue::S(notification.data.alert_type.clone()))
+        .item("sensor_id", AttributeValue::S(notification.data.sensor_uuid.clone()))
+        .item("title", AttributeValue::S(notification.title.clone()))
+        .item("body", AttributeValue::S(notification.body.clone()))
+        .item("payload", AttributeValue::S(serde_json::to_string(&notification.data).unwrap()))
+        .item("delivered", AttributeValue::N(delivered.to_string()))
+        .item("failed", AttributeValue::N(failed.to_string()))
+        .item("read", AttributeValue::Bool(false))
+        .item("timestamp", AttributeValue::N(now.timestamp().to_string()))
+        .send()
+        .await;
+
+    if let Err(err) = result {
+        println!("{:?}", err); // Losing the inbox entry must not block the notification
+    }
+}
@@ -1,47 +1,21 @@
+mod alert_state;
+mod history;
+mod notification;
+mod plant;
+mod sharing;
+
+use alert_state::Decision;
 use aws_config::load_from_env;
 use aws_sdk_dynamodb::types::AttributeValue;
+use chrono::Utc;
+use helper::connectivity::ConnectivityEvent;
 use lambda_runtime::{run, service_fn, Error, LambdaEvent};
-use serde::{Serialize, Deserialize};
+use notification::PushNotification;
+use serde::Deserialize;
 use std::collections::HashMap;
 
 const SNS_DEVICES_TABLE: &str = "notification_devices";
 
-/*
-    {
-        "notification": {
-            "title":"This is the needed title for system display",
-            "body":"This is the needed body for system display"
-        },
-        "data" : {
-            "custom_app_field1" : "Whatever",
-            "custom_app_field2" : "Whenever"
-        }
-    }
-*/
-
-#[derive(Debug, Serialize)]
-struct Notification {
-    title: String,
-    body: String,
-}
-
-#[derive(Debug, Serialize)]
-struct Data {
-    custom_app_field1: String,
-    custom_app_field2: String,
-}
-
-#[derive(Debug, Serialize)]
-struct SNSNotification {
-    notification: Notification,
-    data: Data,
-}
-
-#[derive(Debug, Serialize)]
-struct SNSProtocolMessage {
-    GCM: String
-}
-
 /*
 
     Example MQTT message:
@@ -58,54 +32,39 @@ struct SNSProtocolMessage {
         "uuid": "297a0620-3b4d-40ed-b407-2216eb0d"
     }
 
+    Every reading is checked against the plant config in the plants table,
+    the notification flags are only used when the plant is not configured.
+    This way we can also detect when a sensor is back to normal.
+
+    user_id is the owner of the sensor: cooldown, quiet hours and alert state are the owner ones,
+    the notifications are also sent to every user the sensor is shared with.
+
+    Alerts suppressed during quiet hours are sent in a digest with the first reading of any sensor
+    of the user after quiet hours end, not at the end of quiet hours: nothing runs without a reading.
+    If every sensor of the user stays silent after the night, the digest waits for the next reading.
+
 */
 
 #[derive(Deserialize, Clone)]
-struct Request {
-    user_id: String,
-    is_temperature_notification: bool,
-    is_humidity_notification: bool,
-    temperature: i8,
-    humidity: i8,
-    soil_humidity: i8,
-    hour: bool,
-    media_month: bool,
-    uuid: String,
+#[serde(untagged)]
+pub enum Event {
+    Connectivity(ConnectivityEvent),
+    Reading(Request),
 }
 
-fn create_notification_temperature(mqtt_message: Request) -> SNSProtocolMessage {
-    let notification_title = if mqtt_message.is_temperature_notification && mqtt_message.is_humidity_notification {
-        "Agromate avvisi sensori"
-    } else if mqtt_message.is_temperature_notification {
-        "Agromate avviso temperatura"
-    } else if mqtt_message.is_humidity_notification {
-        "Agromate avviso umidità"
-    } else {""}.to_string();
-
-    let notification_body = if mqtt_message.is_temperature_notification && mqtt_message.is_humidity_notification {
-        "I sensori di umidità e temperatura indicano valore inadeguati per la tua pianta! Controlla la serra e mettila in un luogo più fresco".to_string()
-    } else if mqtt_message.is_temperature_notification {
-        format!("La temperature è fuori dal range, temperatura attuale: {}", mqtt_message.temperature)
-    } else if mqtt_message.is_humidity_notification {
-        format!("L'umidità è fuori dal range, umidità attuale: {}", mqtt_message.humidity)
-    } else {"".to_string()};
-
-    let notification = SNSNotification {
-        notification: Notification {
-            title: notification_title,
-            body: notification_body,
-        },
-        data: Data {
-            custom_app_field1: "not_used".to_string(),
-            custom_app_field2: "not_used".to_string(),
-        },
-    };
-    SNSProtocolMessage {
-        GCM: serde_json::to_string(&notification).unwrap()
-    }
+#[derive(Deserialize, Clone)]
+pub struct Request {
+    pub user_id: String,
+    pub is_temperature_notification: bool,
+    pub is_humidity_notification: bool,
+    pub temperature: i8,
+    pub humidity: i8,
+    pub soil_humidity: i8,
+    pub hour: bool,
+    pub media_month: bool,
+    pub uuid: String,
 }
 
-
 #[derive(Debug, Clone)]
 struct NotificationDevice {
     arn: String,
@@ -119,41 +78,133 @@ impl Into<NotificationDevice> for &HashMap<String, AttributeValue> {
     }
 }
 
-async fn function_handler(event: LambdaEvent<Request>) -> Result<(), Error> {
-    let shared_config = load_from_env().await;
-    let client = aws_sdk_sns::Client::new(&shared_config);
-    let dynamodb_client = aws_sdk_dynamodb::Client::new(&shared_config);
-
+async fn get_notification_devices(dynamodb_client: &aws_sdk_dynamodb::Client, user_id: &str) -> Result<Vec<NotificationDevice>, Error> {
     let results = dynamodb_client
         .query()
         .table_name(SNS_DEVICES_TABLE)
         .key_condition_expression("#user_id = :uid")
         .expression_attribute_names("#user_id", "user_id")
-        .expression_attribute_values(":uid", AttributeValue::S(event.clone().payload.user_id))
+        .expression_attribute_values(":uid", AttributeValue::S(user_id.to_string()))
         .send()
         .await?;
 
-    if let Some(items) = results.items {
-        let notification_devices: Vec<NotificationDevice> = items
+    Ok(match results.items {
+        Some(items) => items
             .iter()
             .map(|v: &HashMap<String, AttributeValue>| v.into())
-            .collect();
-        if notification_devices.len() == 0 {
-            panic!("No devices were found");
-        }
-        let payload = create_notification_temperature(event.payload);
+            .collect(),
+        None => Vec::new(),
+    })
+}
 
-        let _ = client
+/// Send the notification to every recipient
+async fn publish_all(client: &aws_sdk_sns::Client, dynamodb_client: &aws_sdk_dynamodb::Client, recipients: &[(String, Vec<NotificationDevice>)], notification: PushNotification) {
+    for (user_id, devices) in recipients {
+        publish(client, dynamodb_client, user_id, devices, notification.clone()).await;
+    }
+}
+
+/// Send the notification to every device of the user and save it in the inbox
+async fn publish(client: &aws_sdk_sns::Client, dynamodb_client: &aws_sdk_dynamodb::Client, user_id: &str, devices: &[NotificationDevice], notification: PushNotification) {
+    let message = serde_json::to_string(&notification.to_protocol_message()).expect("Serialization failed");
+    let mut delivered = 0;
+    for device in devices {    // The same message works for every platform, SNS picks the right payload
+        let result = client
             .publish()
-            .target_arn(notification_devices[0].arn.clone())
+            .target_arn(device.arn.clone())
             .message_structure("json")
-            .message(serde_json::to_string(&payload).expect("Serialization failed"))
+            .message(message.clone())
             .send()
-            .await
-            .unwrap();
-    } else {
+            .await;
+        match result {
+            Ok(_) => delivered += 1,
+            Err(err) => println!("Error sending notification to {}: {:?}", device.arn, err),
+        }
+    }
+    history::save_notification(dynamodb_client, user_id, &notification, delivered, devices.len() - delivered).await;
+}
+
+/// Notification devices of the owner and of every user the sensor is shared with
+async fn get_recipients(dynamodb_client: &aws_sdk_dynamodb::Client, owner_id: &str, uuid: &str) -> Result<Vec<(String, Vec<NotificationDevice>)>, Error> {
+    let mut recipients = Vec::new();
+    for user_id in sharing::get_recipients(dynamodb_client, owner_id, uuid).await {
+        let devices = get_notification_devices(dynamodb_client, &user_id).await?;
+        if devices.len() > 0 {
+            recipients.push((user_id, devices));
+        }
+    }
+    if recipients.len() == 0 {
         panic!("No devices were found");
     }
+    Ok(recipients)
+}
+
+/// Offline and online notifications are sent as soon as they happen, also during quiet hours
+async fn handle_connectivity(client: &aws_sdk_sns::Client, dynamodb_client: &aws_sdk_dynamodb::Client, event: ConnectivityEvent) -> Result<(), Error> {
+    let recipients = get_recipients(dynamodb_client, &event.user_id, &event.uuid).await?;
+    let payload = notification::create_notification_connectivity(
+        &event.uuid,
+        event.device_name.as_deref(),
+        event.online,
+        event.last_seen,
+        Utc::now().timestamp(),
+    );
+    publish_all(client, dynamodb_client, &recipients, payload).await;
+    Ok(())
+}
+
+async fn function_handler(event: LambdaEvent<Event>) -> Result<(), Error> {
+    let shared_config = load_from_env().await;
+    let client = aws_sdk_sns::Client::new(&shared_config);
+    let dynamodb_client = aws_sdk_dynamodb::Client::new(&shared_config);
+    let mqtt_message = match event.payload {
+        Event::Connectivity(event) => return handle_connectivity(&client, &dynamodb_client, event).await,
+        Event::Reading(reading) => reading,
+    };
+    let now = Utc::now();
+
+    let recipients = get_recipients(&dynamodb_client, &mqtt_message.user_id, &mqtt_message.uuid).await?;
+
+    let settings = alert_state::get_settings(&dynamodb_client, &mqtt_message.user_id).await;
+    let plant = plant::get_plant(&dynamodb_client, &mqtt_message.user_id, &mqtt_message.uuid).await;
+    let mut to_send = Vec::new();
+    let mut recovered = Vec::new();
+    let mut suppressed = false;
+    for (alert_type, firing) in plant::evaluate_alerts(&mqtt_message, plant.as_ref()) {
+        let mut state = alert_state::get_alert_state(&dynamodb_client, &mqtt_message.user_id, &mqtt_message.uuid, alert_type).await;
+        if !firing && !state.active {
+            continue;   // Still normal, nothing changed
+        }
+        match state.evaluate(firing, now, &settings) {
+            Decision::Send => to_send.push(alert_type),
+            Decision::Recovery => recovered.push(alert_type),
+            Decision::Suppress => suppressed = true,
+            Decision::Skip => {}
+        }
+        alert_state::save_alert_state(&dynamodb_client, &mqtt_message.user_id, &state).await;
+    }
+
+    if suppressed && !settings.digest_pending {
+        alert_state::set_digest_pending(&dynamodb_client, &mqtt_message.user_id, true).await;
+    } else if settings.digest_pending && !settings.is_quiet(now) {
+        // First message after quiet hours, send what the user missed during the night.
+        // This is the only place the digest is sent, see the comment at the top
+        let suppressed_alerts = alert_state::take_suppressed_alerts(&dynamodb_client, &mqtt_message.user_id).await;
+        alert_state::set_digest_pending(&dynamodb_client, &mqtt_message.user_id, false).await;
+        if suppressed_alerts.len() > 0 {
+            let payload = notification::create_notification_digest(&mqtt_message, &suppressed_alerts, now.timestamp());
+            publish_all(&client, &dynamodb_client, &recipients, payload).await;
+        }
+    }
+
+    for alert_type in to_send {
+        let payload = notification::create_notification_alert(&mqtt_message, plant.as_ref(), alert_type, now.timestamp());
+        publish_all(&client, &dynamodb_client, &recipients, payload).await;
+    }
+    for alert_type in recovered {
+        let payload = notification::create_notification_recovery(&mqtt_message, plant.as_ref(), alert_type, now.timestamp());
+        publish_all(&client, &dynamodb_client, &recipients, payload).await;
+    }
 
     Ok(())
 }
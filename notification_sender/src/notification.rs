@@ -0,0 +1,240 @@
+use crate::alert_state::{AlertState, AlertType};
+use crate::plant::{measured_value, Plant};
+use crate::Request;
+use serde::Serialize;
+
+/*
+    {
+        "notification": {
+            "title":"This is the needed title for system display",
+            "body":"This is the needed body for system display"
+        },
+        "data" : {
+            "kind": "alert",
+            "alert_type": "temperature",
+            "sensor_uuid": "297a0620-3b4d-40ed-b407-2216eb0d",
+            "plant_name": "Basilico",
+            "value": "31",
+            "threshold": "25",
+            "timestamp": "1696834199"
+        }
+    }
+
+    The data payload is documented for the app in docs/notifications.yaml.
+    On iOS the same payload is sent with the APNs format:
+
+    {
+        "aps": {
+            "alert": {
+                "title": "This is the needed title for system display",
+                "body": "This is the needed body for system display"
+            }
+        },
+        "data": { ... }
+    }
+*/
+
+#[derive(Debug, Clone, Serialize)]
+struct Notification {
+    title: String,
+    body: String,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NotificationKind {
+    Alert,
+    Recovery,
+    Digest,
+    Offline,    // The sensor stopped sending readings
+    Online,     // The sensor is sending readings again
+}
+
+/// Data used by the app to open the right plant screen.
+/// FCM only accepts strings in data, so every value is already formatted.
+#[derive(Debug, Clone, Serialize)]
+pub struct NotificationData {
+    pub kind: NotificationKind,
+    pub alert_type: String,     // temperature, humidity, soil_humidity or empty for digest, offline and online
+    pub sensor_uuid: String,
+    pub plant_name: String,
+    pub value: String,          // Measured value
+    pub threshold: String,      // Limit crossed by value
+    pub timestamp: String,      // Unix timestamp of when notification_sender processed the reading, the MQTT message has none
+}
+
+impl NotificationKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotificationKind::Alert => "alert",
+            NotificationKind::Recovery => "recovery",
+            NotificationKind::Digest => "digest",
+            NotificationKind::Offline => "offline",
+            NotificationKind::Online => "online",
+        }
+    }
+}
+
+/// Notification ready to be sent to SNS and saved in the user inbox
+#[derive(Debug, Clone)]
+pub struct PushNotification {
+    pub title: String,
+    pub body: String,
+    pub data: NotificationData,
+}
+
+#[derive(Debug, Serialize)]
+struct SNSNotification {
+    notification: Notification,
+    data: NotificationData,
+}
+
+#[derive(Debug, Serialize)]
+struct APS {
+    alert: Notification,
+}
+
+#[derive(Debug, Serialize)]
+struct APNSNotification {
+    aps: APS,
+    data: NotificationData,
+}
+
+/// SNS picks the key matching the platform of every endpoint
+#[allow(non_snake_case)]
+#[derive(Debug, Serialize)]
+pub struct SNSProtocolMessage {
+    default: String,    // Used by platforms without a dedicated key
+    GCM: String,        // Android and web (FCM)
+    APNS: String,
+    APNS_SANDBOX: String,   // Development builds of the iOS app
+}
+
+fn threshold(plant: Option<&Plant>, alert_type: AlertType, value: f32) -> String {
+    match plant.and_then(|plant| plant.range(alert_type)) {
+        Some((default, limit, _)) => Plant::threshold(default, limit, value).to_string(),
+        None => "".to_string(),
+    }
+}
+
+fn create_data(kind: NotificationKind, alert_type: AlertType, mqtt_message: &Request, plant: Option<&Plant>, timestamp: i64) -> NotificationData {
+    let value = measured_value(mqtt_message, alert_type);
+    NotificationData {
+        kind,
+        alert_type: alert_type.as_str().to_string(),
+        sensor_uuid: mqtt_message.uuid.clone(),
+        plant_name: plant.and_then(|v| v.plant_name.clone()).unwrap_or_default(),
+        value: value.to_string(),
+        threshold: threshold(plant, alert_type, value),
+        timestamp: timestamp.to_string(),
+    }
+}
+
+pub fn create_notification_alert(mqtt_message: &Request, plant: Option<&Plant>, alert_type: AlertType, timestamp: i64) -> PushNotification {
+    let (notification_title, notification_body) = match alert_type {
+        AlertType::Temperature => (
+            "Agromate avviso temperatura",
+            format!("La temperature è fuori dal range, temperatura attuale: {}", mqtt_message.temperature),
+        ),
+        AlertType::Humidity => (
+            "Agromate avviso umidità",
+            format!("L'umidità è fuori dal range, umidità attuale: {}", mqtt_message.humidity),
+        ),
+        AlertType::SoilHumidity => (
+            "Agromate avviso umidità del terreno",
+            format!("L'umidità del terreno è fuori dal range, umidità attuale: {}", mqtt_message.soil_humidity),
+        ),
+    };
+    let data = create_data(NotificationKind::Alert, alert_type, mqtt_message, plant, timestamp);
+    PushNotification {
+        title: notification_title.to_string(),
+        body: notification_body,
+        data,
+    }
+}
+
+pub fn create_notification_recovery(mqtt_message: &Request, plant: Option<&Plant>, alert_type: AlertType, timestamp: i64) -> PushNotification {
+    let notification_body = match alert_type {
+        AlertType::Temperature => format!("La temperatura è tornata nella norma, temperatura attuale: {}", mqtt_message.temperature),
+        AlertType::Humidity => format!("L'umidità è tornata nella norma, umidità attuale: {}", mqtt_message.humidity),
+        AlertType::SoilHumidity => format!("L'umidità del terreno è tornata nella norma, umidità attuale: {}", mqtt_message.soil_humidity),
+    };
+    let data = create_data(NotificationKind::Recovery, alert_type, mqtt_message, plant, timestamp);
+    PushNotification {
+        title: "Agromate tutto ok".to_string(),
+        body: notification_body,
+        data,
+    }
+}
+
+pub fn create_notification_digest(mqtt_message: &Request, suppressed: &[AlertState], timestamp: i64) -> PushNotification {
+    let count = |alert_type: AlertType| -> i64 { suppressed.iter().filter(|v| v.alert_type == alert_type).map(|v| v.suppressed).sum() };
+    let notification_body = format!(
+        "Durante le ore di silenzio abbiamo rilevato {} avvisi di temperatura, {} avvisi di umidità e {} avvisi di umidità del terreno",
+        count(AlertType::Temperature),
+        count(AlertType::Humidity),
+        count(AlertType::SoilHumidity)
+    );
+    let data = NotificationData {
+        kind: NotificationKind::Digest,
+        alert_type: "".to_string(),
+        sensor_uuid: mqtt_message.uuid.clone(),
+        plant_name: "".to_string(),
+        value: "".to_string(),
+        threshold: "".to_string(),
+        timestamp: timestamp.to_string(),
+    };
+    PushNotification {
+        title: "Agromate riepilogo notturno".to_string(),
+        body: notification_body,
+        data,
+    }
+}
+
+/// The sensor went offline or came back, value is the timestamp of the last reading before the silence
+pub fn create_notification_connectivity(uuid: &str, device_name: Option<&str>, online: bool, last_seen: i64, timestamp: i64) -> PushNotification {
+    let name = device_name.unwrap_or("Il sensore");
+    let (kind, title, body) = if online {
+        (NotificationKind::Online, "Agromate sensore di nuovo online", format!("{} ha ripreso a inviare letture", name))
+    } else {
+        (NotificationKind::Offline, "Agromate sensore offline", format!("{} non invia letture da un po' di tempo", name))
+    };
+    let data = NotificationData {
+        kind,
+        alert_type: "".to_string(),
+        sensor_uuid: uuid.to_string(),
+        plant_name: "".to_string(),
+        value: last_seen.to_string(),
+        threshold: "".to_string(),
+        timestamp: timestamp.to_string(),
+    };
+    PushNotification {
+        title: title.to_string(),
+        body,
+        data,
+    }
+}
+
+impl PushNotification {
+    pub fn to_protocol_message(&self) -> SNSProtocolMessage {
+        let notification = Notification {
+            title: self.title.clone(),
+            body: self.body.clone(),
+        };
+        let gcm_notification = SNSNotification {
+            notification: notification.clone(),
+            data: self.data.clone(),
+        };
+        let apns_notification = APNSNotification {
+            aps: APS { alert: notification },
+            data: self.data.clone(),
+        };
+        let apns = serde_json::to_string(&apns_notification).unwrap();
+        SNSProtocolMessage {
+            default: self.body.clone(),
+            GCM: serde_json::to_string(&gcm_notification).unwrap(),
+            APNS: apns.clone(),
+            APNS_SANDBOX: apns,
+        }
+    }
+}
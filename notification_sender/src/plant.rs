@@ -0,0 +1,134 @@
+use crate::alert_state::AlertType;
+use crate::Request;
+use aws_sdk_dynamodb::{types::AttributeValue, Client};
+use std::collections::HashMap;
+
+const PLANTS_TABLE: &str = "plants"; // Written by plant_info_api
+
+/// Subset of the plant config saved by plant_info_api that we need for notifications.
+/// Values missing or malformed in the table are None and their alert is not evaluated
+#[derive(Debug, Clone)]
+pub struct Plant {
+    pub plant_name: Option<String>,
+    pub default_temperature: Option<f32>,
+    pub temperature_limit: Option<f32>,
+    pub notify_wrong_temperature: Option<bool>,
+    pub default_humidity: Option<f32>,
+    pub humidity_limit: Option<f32>,
+    pub notify_wrong_humidity: Option<bool>,
+    pub default_precipitation: Option<f32>,    // Soil humidity
+    pub precipitation_limit: Option<f32>,
+    pub notify_wrong_soil_humidity: Option<bool>,
+}
+
+fn attribute_to_f32(item: &HashMap<String, AttributeValue>, key: &str) -> Option<f32> {
+    item.get(key)?.as_n().ok()?.parse::<f32>().ok()
+}
+
+fn attribute_to_bool(item: &HashMap<String, AttributeValue>, key: &str) -> Option<bool> {
+    item.get(key)?.as_bool().ok().copied()
+}
+
+fn plant_name(item: &HashMap<String, AttributeValue>) -> Option<String> {
+    item.get("plant_name")?.as_s().ok().cloned()
+}
+
+impl From<&HashMap<String, AttributeValue>> for Plant {
+    fn from(item: &HashMap<String, AttributeValue>) -> Self {
+        Plant {
+            plant_name: plant_name(item),
+            default_temperature: attribute_to_f32(item, "default_temperature"),
+            temperature_limit: attribute_to_f32(item, "temperature_limit"),
+            notify_wrong_temperature: attribute_to_bool(item, "notify_wrong_temperature"),
+            default_humidity: attribute_to_f32(item, "default_humidity"),
+            humidity_limit: attribute_to_f32(item, "humidity_limit"),
+            notify_wrong_humidity: attribute_to_bool(item, "notify_wrong_humidity"),
+            default_precipitation: attribute_to_f32(item, "default_precipitation"),
+            precipitation_limit: attribute_to_f32(item, "precipitation_limit"),
+            notify_wrong_soil_humidity: attribute_to_bool(item, "notify_wrong_soil_humidity"),
+        }
+    }
+}
+
+impl Plant {
+    /// Limit crossed by value, the upper one if value is above the default otherwise the lower one
+    pub fn threshold(default: f32, limit: f32, value: f32) -> f32 {
+        if value >= default {
+            default + limit
+        } else {
+            default - limit
+        }
+    }
+
+    /// Default value, allowed distance from it and if the user wants to be notified.
+    /// None if any of them is missing from the plant config
+    pub fn range(&self, alert_type: AlertType) -> Option<(f32, f32, bool)> {
+        match alert_type {
+            AlertType::Temperature => Some((self.default_temperature?, self.temperature_limit?, self.notify_wrong_temperature?)),
+            AlertType::Humidity => Some((self.default_humidity?, self.humidity_limit?, self.notify_wrong_humidity?)),
+            AlertType::SoilHumidity => Some((self.default_precipitation?, self.precipitation_limit?, self.notify_wrong_soil_humidity?)),
+        }
+    }
+
+    fn is_out_of_range(&self, alert_type: AlertType, value: f32) -> Option<bool> {
+        let (default, limit, notify) = self.range(alert_type)?;
+        Some(notify && (value - default).abs() > limit)
+    }
+}
+
+pub fn measured_value(mqtt_message: &Request, alert_type: AlertType) -> f32 {
+    match alert_type {
+        AlertType::Temperature => mqtt_message.temperature as f32,
+        AlertType::Humidity => mqtt_message.humidity as f32,
+        AlertType::SoilHumidity => mqtt_message.soil_humidity as f32,
+    }
+}
+
+/// Decide which alerts fire using the plant config saved by the user.
+/// The flags sent by the ESP8266 are only used when the plant is not configured.
+/// Alerts whose range is incomplete in the plant config are skipped.
+pub fn evaluate_alerts(mqtt_message: &Request, plant: Option<&Plant>) -> Vec<(AlertType, bool)> {
+    let alert_types = [AlertType::Temperature, AlertType::Humidity, AlertType::SoilHumidity];
+    match plant {
+        Some(plant) => alert_types
+            .into_iter()
+            .filter_map(|alert_type| {
+                let Some(firing) = plant.is_out_of_range(alert_type, measured_value(mqtt_message, alert_type)) else {
+                    println!("Plant of device {} has an incomplete {} range, alert skipped", mqtt_message.uuid, alert_type.as_str());
+                    return None;
+                };
+                let hint = match alert_type {
+                    AlertType::Temperature => mqtt_message.is_temperature_notification,
+                    AlertType::Humidity => mqtt_message.is_humidity_notification,
+                    AlertType::SoilHumidity => firing,    // The device never reports it
+                };
+                if hint != firing {
+                    println!("Device {} disagrees on {} alert, device: {}, backend: {}", mqtt_message.uuid, alert_type.as_str(), hint, firing);
+                }
+                Some((alert_type, firing))
+            })
+            .collect(),
+        None => vec![
+            (AlertType::Temperature, mqtt_message.is_temperature_notification),
+            (AlertType::Humidity, mqtt_message.is_humidity_notification),
+        ],
+    }
+}
+
+pub async fn get_plant(client: &Client, user_id: &str, sensor_id: &str) -> Option<Plant> {
+    let result = client
+        .get_item()
+        .table_name(PLANTS_TABLE)
+        .key("user_id", AttributeValue::S(user_id.to_string()))
+        .key("sensor_id", AttributeValue::S(sensor_id.to_string()))
+        .send()
+        .await;
+
+    match result {
+        Ok(output) => output.item().map(|item| item.into()),
+        Err(err) => {
+            println!("{:?}", err);
+            None
+        }
+    }
+}
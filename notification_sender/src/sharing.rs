@@ -0,0 +1,29 @@
+use aws_sdk_dynamodb::{types::AttributeValue, Client};
+use helper::sharing::DEVICE_GRANTS_TABLE;
+
+/// Users receiving the alerts of the sensor: the owner and every user it is shared with
+pub async fn get_recipients(client: &Client, owner_id: &str, uuid: &str) -> Vec<String> {
+    let mut recipients = vec![owner_id.to_string()];
+    let results = client
+        .query()
+        .table_name(DEVICE_GRANTS_TABLE)
+        .key_condition_expression("#device_id = :device_id")
+        .expression_attribute_names("#device_id", "device_id")
+        .expression_attribute_values(":device_id", AttributeValue::S(uuid.to_string()))
+        .send()
+        .await;
+
+    match results {
+        Ok(output) => {
+            for item in output.items.unwrap_or_default() {
+                if let Some(user_id) = item.get("user_id").and_then(|v| v.as_s().ok()) {
+                    if !recipients.contains(user_id) {
+                        recipients.push(user_id.to_string());
+                    }
+                }
+            }
+        }
+        Err(err) => println!("{:?}", err),   // The owner still gets the alert
+    }
+    recipients
+}
@@ -0,0 +1,131 @@
+use aws_config::load_from_env;
+use aws_sdk_dynamodb::{types::AttributeValue, Client};
+use chrono::Utc;
+use helper::connectivity::{notify_connectivity, ConnectivityEvent};
+use lambda_runtime::{run, service_fn, Error, LambdaEvent};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use tokio_stream::StreamExt;
+
+const DEVICE_TABLE: &str = "devices";   // last_seen is updated by mqtt_month_media_processor at every reading
+const DEFAULT_OFFLINE_AFTER_MINUTES: i64 = 60;  // ESP8266s send a reading at least every hour
+
+/// Scheduled by EventBridge, the event is not used
+#[derive(Deserialize)]
+struct Request {}
+
+#[derive(Serialize)]
+struct Response {
+    req_id: String,
+    msg: String,
+}
+
+/// Silence after which a device is offline, can be changed with the OFFLINE_AFTER_MINUTES environment variable
+fn offline_after_minutes() -> i64 {
+    env::var("OFFLINE_AFTER_MINUTES")
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(DEFAULT_OFFLINE_AFTER_MINUTES)
+}
+
+fn attribute_to_string(item: &HashMap<String, AttributeValue>, key: &str) -> Option<String> {
+    item.get(key).and_then(|v| v.as_s().ok()).map(|v| v.to_string())
+}
+
+/// Active devices not heard since the threshold, devices added before the registry have no status and are active
+async fn get_silent_devices(client: &Client, threshold: i64) -> Result<Vec<HashMap<String, AttributeValue>>, Error> {
+    let items: Result<Vec<_>, _> = client
+    .scan()
+    .table_name(DEVICE_TABLE)
+    .filter_expression("(attribute_not_exists(#status) OR #status = :active) AND last_seen < :threshold")
+    .expression_attribute_names("#status", "status")
+    .expression_attribute_values(":active", AttributeValue::S("active".to_string()))
+    .expression_attribute_values(":threshold", AttributeValue::N(threshold.to_string()))
+    .into_paginator()
+    .items()
+    .send()
+    .collect()
+    .await;
+    Ok(items?)
+}
+
+/// Mark the device offline, false if a reading arrived in the meantime
+async fn mark_offline(client: &Client, user_id: &str, device_id: &str, threshold: i64) -> Result<bool, Error> {
+    let result = client
+        .update_item()
+        .table_name(DEVICE_TABLE)
+        .key("user_id", AttributeValue::S(user_id.to_string()))
+        .key("device_id", AttributeValue::S(device_id.to_string()))
+        .update_expression("SET #status = :offline")
+        .condition_expression("(attribute_not_exists(#status) OR #status = :active) AND last_seen < :threshold")
+        .expression_attribute_names("#status", "status")
+        .expression_attribute_values(":offline", AttributeValue::S("offline".to_string()))
+        .expression_attribute_values(":active", AttributeValue::S("active".to_string()))
+        .expression_attribute_values(":threshold", AttributeValue::N(threshold.to_string()))
+        .send()
+        .await;
+
+    match result {
+        Ok(_out) => Ok(true),
+        Err(err) => {
+            if err.as_service_error().map(|e| e.is_conditional_check_failed_exception()) == Some(true) {
+                Ok(false)
+            } else {
+                Err(err.into())
+            }
+        }
+    }
+}
+
+async fn function_handler(event: LambdaEvent<Request>) -> Result<Response, Error> {
+    let shared_config = load_from_env().await;
+    let client = Client::new(&shared_config);
+    let lambda_client = aws_sdk_lambda::Client::new(&shared_config);
+    let threshold = Utc::now().timestamp() - offline_after_minutes() * 60;
+
+    let mut offline = 0;
+    for device in get_silent_devices(&client, threshold).await? {
+        let (user_id, device_id) = match (attribute_to_string(&device, "user_id"), attribute_to_string(&device, "device_id")) {
+            (Some(user_id), Some(device_id)) => (user_id, device_id),
+            _ => continue,
+        };
+        // The status changes once, so the user gets a single notification until the device is back
+        if !mark_offline(&client, &user_id, &device_id, threshold).await? {
+            continue;
+        }
+        let event = ConnectivityEvent {
+            user_id,
+            uuid: device_id,
+            online: false,
+            last_seen: device
+                .get("last_seen")
+                .and_then(|v| v.as_n().ok())
+                .and_then(|v| v.parse::<i64>().ok())
+                .unwrap_or_default(),
+            device_name: attribute_to_string(&device, "name"),
+        };
+        notify_connectivity(&lambda_client, &event).await;
+        offline += 1;
+    }
+
+    let resp = Response {
+        req_id: event.context.request_id,
+        msg: format!("{} devices went offline.", offline),
+    };
+    Ok(resp)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::ERROR)
+        // disable printing the name of the module in every log line.
+        .with_target(false)
+        // disabling time is handy because CloudWatch will add the ingestion time.
+        .without_time()
+        .init();
+
+    run(service_fn(function_handler)).await
+}
@@ -1,12 +1,54 @@
-use super::models::PostRequest;
+use super::models::{Platform, PostRequest};
 use aws_sdk_dynamodb::{types::AttributeValue, Client};
 use aws_sdk_iotdataplane as iotdataplane;
 use aws_sdk_iotdataplane::primitives::Blob;
 use helper::get_user_id;
+use helper::sharing::{Access, Role, DEVICE_GRANTS_TABLE};
 
 const TABLE_NAME: &str = "plants"; // DynamoDB table name
 const SNS_ARN: &str = "arn:aws:sns:eu-central-1:284535252702:app/GCM/sensor_notification";
+const SNS_APNS_ARN: &str = "arn:aws:sns:eu-central-1:284535252702:app/APNS/sensor_notification";
+const SNS_APNS_SANDBOX_ARN: &str = "arn:aws:sns:eu-central-1:284535252702:app/APNS_SANDBOX/sensor_notification";
 const SNS_ARN_TABLE: &str = "notification_devices"; // Collection of all notification registred devices
+const DEVICE_TABLE: &str = "devices";   // Written by device_api
+
+/// Whose plant the user works on: the own one, or the one of the owner if the sensor is shared with the user.
+/// None if the sensor is neither owned by nor shared with the user
+pub async fn get_access(client: &Client, uid: &str, sensor_id: &str) -> Result<Option<Access>, String> {
+    let device = client
+        .get_item()
+        .table_name(DEVICE_TABLE)
+        .key("user_id", AttributeValue::S(uid.to_string()))
+        .key("device_id", AttributeValue::S(sensor_id.to_string()))
+        .send()
+        .await
+        .map_err(|err| {
+            println!("{:?}", err);
+            "Something went wrong while finding sensor in DB".to_string()
+        })?;
+    if device.item().is_some() {
+        return Ok(Some(Access { role: Role::Owner, owner_id: uid.to_string() }));
+    }
+
+    let grant = client
+        .get_item()
+        .table_name(DEVICE_GRANTS_TABLE)
+        .key("device_id", AttributeValue::S(sensor_id.to_string()))
+        .key("user_id", AttributeValue::S(uid.to_string()))
+        .send()
+        .await
+        .map_err(|err| {
+            println!("{:?}", err);
+            "Something went wrong while finding sensor in DB".to_string()
+        })?;
+    Ok(grant.item().and_then(|item| {
+        let role = Role::parse(item.get("role")?.as_s().ok()?)?;
+        let owner_id = item.get("owner_id")?.as_s().ok()?.to_string();
+        // Grants saved as owner before only viewer and editor were accepted can still edit, not share
+        let role = if role.can_be_granted() { role } else { Role::Editor };
+        Some(Access { role, owner_id })
+    }))
+}
 
 async fn filter_uid(client: Client, uid: &str) -> Result<aws_sdk_dynamodb::operation::query::QueryOutput, aws_sdk_dynamodb::error::SdkError<aws_sdk_dynamodb::operation::query::QueryError>>
 {
@@ -49,9 +91,17 @@ pub async fn get_plant(client: Client, uid: &str, sensor_id: &str) -> Result<Str
     }
 }
 
-async fn add_device_to_notification(device_token: String, uid: String, sns_client: aws_sdk_sns::Client, dynamodb_client: Client) {
+fn platform_application_arn(platform: Platform) -> &'static str {
+    match platform {
+        Platform::Android | Platform::Web => SNS_ARN,
+        Platform::Ios => SNS_APNS_ARN,
+        Platform::IosSandbox => SNS_APNS_SANDBOX_ARN,
+    }
+}
+
+async fn add_device_to_notification(device_token: String, platform: Platform, uid: String, sns_client: aws_sdk_sns::Client, dynamodb_client: Client) {
     let result = sns_client.create_platform_endpoint()
-        .platform_application_arn(SNS_ARN)
+        .platform_application_arn(platform_application_arn(platform))
         .token(device_token)
         .send()
         .await.expect("Error adding device to SNS");
@@ -60,15 +110,17 @@ async fn add_device_to_notification(device_token: String, uid: String, sns_clien
             .table_name(SNS_ARN_TABLE)
             .item("user_id", AttributeValue::S(uid))
             .item("arn", AttributeValue::S(result.endpoint_arn.unwrap()))
+            .item("platform", AttributeValue::S(platform.as_str().to_string()))
             .send().await.expect("Error adding device notification to DynamoDB");
 }
 
-pub async fn add_plant(dynamodb_client: Client, iot_data_client: aws_sdk_iotdataplane::Client, sns_client: aws_sdk_sns::Client, request: PostRequest) -> Result<String, String> {
+/// Save the plant of the sensor under owner_id, the notification device is registered for the user of the request
+pub async fn add_plant(dynamodb_client: Client, iot_data_client: aws_sdk_iotdataplane::Client, sns_client: aws_sdk_sns::Client, request: PostRequest, owner_id: &str) -> Result<String, String> {
     let iot_request = request.clone();
     let response = dynamodb_client // Save all details of plant in dynamoDB so our ESP8266 can use it
         .put_item()
         .table_name(TABLE_NAME)
-        .item("user_id", AttributeValue::S(request.clone().user_id))
+        .item("user_id", AttributeValue::S(owner_id.to_string()))
         .item("plant_name", AttributeValue::S(request.plant_name))
         .item("sensor_id", AttributeValue::S(request.sensor_id.clone()))
         // Start Temperature
@@ -133,7 +185,7 @@ pub async fn add_plant(dynamodb_client: Client, iot_data_client: aws_sdk_iotdata
     iot_data_client.publish().topic(format!("sensor/plants/{}", request.sensor_id)).payload(Blob::new(serde_json::to_string(&iot_request).unwrap())).send().await.expect("Error in MQTT publish"); 
 
     if request.notify_wrong_humidity || request.notify_wrong_temperature || request.notify_wrong_soil_humidity {
-        add_device_to_notification(request.device_token, request.user_id, sns_client, dynamodb_client).await;
+        add_device_to_notification(request.device_token, request.platform, request.user_id, sns_client, dynamodb_client).await;
     }
     
     match response {
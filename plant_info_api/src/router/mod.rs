@@ -7,7 +7,7 @@ use models::PostRequest;
 use aws_config::load_from_env;
 use aws_sdk_dynamodb::Client;
 use helper::get_user_id;
-use self::endpoints::{get_plant, add_plant};
+use self::endpoints::{get_access, get_plant, add_plant};
 
 pub async fn router(event: Request) -> Result<Response<Body>, Box<lambda_http::http::Error>>  {   // Router for our HTTP lambda
     let result: Result<Response<Body>, Box<lambda_http::http::Error>> = match event.method() {
@@ -23,10 +23,35 @@ pub async fn router(event: Request) -> Result<Response<Body>, Box<lambda_http::h
             let mut body_parsed: PostRequest = serde_json::from_str::<PostRequest>(body_string).unwrap();
             body_parsed.user_id = get_user_id(&event);  // Fill user_id with user_id from JWT token
 
+            let access = match get_access(&client, &body_parsed.user_id, &body_parsed.sensor_id).await {
+                Ok(Some(access)) => access,
+                Ok(None) => {
+                    return Response::builder()
+                    .status(404)
+                    .header("content-type", "text/plain")
+                    .body("Sensor not found".to_string().into())
+                    .map_err(Box::new);
+                }
+                Err(err) => {
+                    return Response::builder()
+                    .status(500)
+                    .header("content-type", "text/plain")
+                    .body(err.into())
+                    .map_err(Box::new);
+                }
+            };
+            if !access.role.can_edit() {
+                return Response::builder()
+                .status(403)
+                .header("content-type", "text/plain")
+                .body("Viewers cannot change the plant".to_string().into())
+                .map_err(Box::new);
+            }
+
             Response::builder()
             .status(200)
             .header("content-type", "application/json")
-            .body(add_plant(client, iot_dataplane_client, sns_client, body_parsed).await.unwrap().into())
+            .body(add_plant(client, iot_dataplane_client, sns_client, body_parsed, &access.owner_id).await.unwrap().into())
             .map_err(Box::new)
         }
   
@@ -37,11 +62,33 @@ pub async fn router(event: Request) -> Result<Response<Body>, Box<lambda_http::h
             let user_id = get_user_id(&event);
             let query_string =  event.query_string_parameters_ref().unwrap();
             let sensor_id = query_string.first("sensor_id").expect("Cannot parse sensor_id");
+            // The plant of a shared sensor is saved under its owner
+            let owner_id = if sensor_id == "NULL" {
+                user_id
+            } else {
+                match get_access(&client, &user_id, sensor_id).await {
+                    Ok(Some(access)) => access.owner_id,
+                    Ok(None) => {
+                        return Response::builder()
+                        .status(404)
+                        .header("content-type", "text/plain")
+                        .body("Sensor not found".to_string().into())
+                        .map_err(Box::new);
+                    }
+                    Err(err) => {
+                        return Response::builder()
+                        .status(500)
+                        .header("content-type", "text/plain")
+                        .body(err.into())
+                        .map_err(Box::new);
+                    }
+                }
+            };
 
             Response::builder()
             .status(200)
             .header("content-type", "application/json")
-            .body(get_plant(client, &user_id, sensor_id).await.unwrap().into())
+            .body(get_plant(client, &owner_id, sensor_id).await.unwrap().into())
             .map_err(Box::new)
             
         }
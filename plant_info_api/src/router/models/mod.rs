@@ -1,13 +1,36 @@
 use std::collections::HashMap;
 use aws_sdk_dynamodb::types::AttributeValue;
 
+#[derive(Clone, Copy, Debug, Default, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Platform {
+    #[default]
+    Android,    // FCM
+    Web,        // FCM web push, same platform application as Android
+    Ios,        // APNs
+    IosSandbox, // APNs for development builds
+}
+
+impl Platform {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Platform::Android => "android",
+            Platform::Web => "web",
+            Platform::Ios => "ios",
+            Platform::IosSandbox => "ios_sandbox",
+        }
+    }
+}
+
 #[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
 pub struct PostRequest {
     #[serde(skip)]  // We don't get this from request body but from JWT
     pub user_id: String,    // Firebase user ID
     pub plant_name: String,
     pub sensor_id: String,  // UUID of ESP8266
-    pub device_token: String,   // FCM device token
+    pub device_token: String,   // FCM or APNs device token
+    #[serde(default, skip_serializing)]
+    pub platform: Platform,     // Platform of device_token, Android if missing
     pub default_temperature: f32,
     pub temperature_limit: f32,
     pub notify_wrong_temperature: bool,
@@ -45,6 +68,7 @@ impl From<&HashMap<String, AttributeValue>> for PostRequest {
             plant_name: AttributeValueToString(value.get("plant_name").unwrap().clone()),
             sensor_id: AttributeValueToString(value.get("sensor_id").unwrap().clone()),  // UUID of ESP8266
             device_token: "".to_string(),   // FCM device token, we don't use it in this DynamoDB table
+            platform: Platform::default(),
             // device_token: AttributeValueToString(value.get("device_token").unwrap().clone()),   // FCM device token
             default_temperature: AttributeValueToFloat(value.get("default_temperature").unwrap().clone()),
             temperature_limit: AttributeValueToFloat(value.get("temperature_limit").unwrap().clone()),
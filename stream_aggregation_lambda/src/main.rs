@@ -0,0 +1,257 @@
+mod stream;
+
+use aws_sdk_dynamodb::operation::transact_write_items::TransactWriteItemsError;
+use aws_sdk_dynamodb::types::{AttributeValue, Put, TransactWriteItem, Update};
+use aws_sdk_dynamodb::Client;
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use helper::retention::{get_plan_policy, DataKind, RetentionPolicy, TTL_ATTRIBUTE};
+use lambda_runtime::{run, service_fn, Error, LambdaEvent};
+use std::env;
+use std::sync::Mutex;
+use stream::{BatchItemFailure, BatchResponse, StreamEvent, StreamRecord};
+
+const AGGREGATE_TABLE: &str = "sensor_aggregates";  // Keyed by uuid + window_key, e.g. hour#2023-10-09T14
+const DEVICE_TABLE: &str = "devices";
+const DEVICE_INDEX: &str = "device_id-index";       // GSI on device_id to find the timezone and the owner of a sensor
+const APPLIED_PREFIX: &str = "applied#";            // Marker rows of the stream records already counted
+const MARKER_TTL: i64 = 2 * 24 * 60 * 60;           // Streams keep records for 24 hours, then they can't be delivered again
+const SENSOR_CACHE_SECONDS: i64 = 5 * 60;           // The owner can change timezone or plan while the lambda is warm
+
+/// Channels we keep running aggregates for
+const CHANNELS: [&str; 3] = ["temperature", "humidity", "soil_humidity"];
+
+// Timezone and retention policy of every sensor already seen with the time of the lookup,
+// kept between warm invocations of the lambda
+static SENSORS: Mutex<Vec<(String, Tz, RetentionPolicy, i64)>> = Mutex::new(Vec::new());
+
+/// Timezone of the devices registered without one, same variable of create_history_lambda
+fn default_timezone() -> Tz {
+    env::var("DEFAULT_TIMEZONE")
+        .ok()
+        .and_then(|name| name.parse::<Tz>().ok())
+        .unwrap_or(Tz::UTC)
+}
+
+/// Timezone of the sensor and retention policy of the plan of its owner
+async fn get_sensor(client: &Client, uuid: &str) -> (Tz, RetentionPolicy) {
+    let now = Utc::now().timestamp();
+    {
+        let mut sensors = SENSORS.lock().unwrap();
+        sensors.retain(|(_, _, _, cached_at)| now - cached_at < SENSOR_CACHE_SECONDS);
+        if let Some((_, timezone, policy, _)) = sensors.iter().find(|(device, _, _, _)| device == uuid) {
+            return (*timezone, *policy);
+        }
+    }
+
+    let results = client
+        .query()
+        .table_name(DEVICE_TABLE)
+        .index_name(DEVICE_INDEX)
+        .key_condition_expression("#device_id = :device_id")
+        .expression_attribute_names("#device_id", "device_id")
+        .expression_attribute_values(":device_id", AttributeValue::S(uuid.to_string()))
+        .limit(1)
+        .send()
+        .await;
+
+    let device = match results {
+        Ok(output) => output.items().and_then(|items| items.first()).cloned(),
+        Err(err) => {
+            println!("{:?}", err);
+            return (default_timezone(), RetentionPolicy::for_plan(None));  // Don't cache it, next time we try again
+        }
+    };
+    let timezone = device
+        .as_ref()
+        .and_then(|item| item.get("timezone"))
+        .and_then(|v| v.as_s().ok())
+        .and_then(|v| v.parse::<Tz>().ok())
+        .unwrap_or_else(default_timezone);
+    let owner = device.as_ref().and_then(|item| item.get("user_id")).and_then(|v| v.as_s().ok());
+    let policy = match owner {
+        Some(owner) => get_plan_policy(client, owner).await,
+        None => RetentionPolicy::for_plan(None),
+    };
+    SENSORS.lock().unwrap().push((uuid.to_string(), timezone, policy, now));
+    (timezone, policy)
+}
+
+/// Window keys of the hour and of the day containing the reading, in the calendar of the owner,
+/// with the timestamp the retention of the aggregate is counted from. None if the timestamp is out of range
+fn window_keys(timestamp: i64, timezone: Tz) -> Option<[(String, i64, DataKind); 2]> {
+    let utc = NaiveDateTime::from_timestamp_opt(timestamp, 0)?;
+    let local = timezone.from_utc_datetime(&utc);
+    Some([
+        (format!("hour#{}", local.format("%Y-%m-%dT%H")), timestamp - timestamp % 3600 + 3600, DataKind::Hourly),
+        (format!("day#{}", local.format("%Y-%m-%d")), timestamp + 24 * 3600, DataKind::Daily),
+    ])
+}
+
+/// ADD of the reading to the samples and sums of a window
+fn add_update(
+    uuid: &str,
+    window_key: &str,
+    retention_from: i64,
+    kind: DataKind,
+    values: &[(&str, f64)],
+    retention_policy: &RetentionPolicy,
+) -> Update {
+    let mut update = Update::builder()
+        .table_name(AGGREGATE_TABLE)
+        .key("uuid", AttributeValue::S(uuid.to_string()))
+        .key("window_key", AttributeValue::S(window_key.to_string()))
+        .expression_attribute_values(":one", AttributeValue::N("1".to_string()));
+
+    let mut add = Vec::new();
+    for (channel, value) in values {
+        add.push(format!("{0}_samples :one, {0}_sum :{0}", channel));
+        update = update.expression_attribute_values(format!(":{}", channel), AttributeValue::N(value.to_string()));
+    }
+    let mut update_expression = format!("ADD {}", add.join(", "));
+    if let Some(expires_at) = retention_policy.expires_at(kind, retention_from) {
+        update_expression.push_str(&format!(" SET {} = :expires_at", TTL_ATTRIBUTE));
+        update = update.expression_attribute_values(":expires_at", AttributeValue::N(expires_at.to_string()));
+    }
+    update.update_expression(update_expression).build()
+}
+
+/// Add the reading to the samples and sums of every window at most once.
+/// The marker row of the stream record is written in the same transaction, so when Lambda
+/// delivers the record again the transaction is canceled and nothing is counted twice
+async fn add_to_windows(
+    client: &Client,
+    uuid: &str,
+    sequence_number: &str,
+    windows: &[(String, i64, DataKind)],
+    values: &[(&str, f64)],
+    retention_policy: &RetentionPolicy,
+) -> Result<(), Error> {
+    let marker = Put::builder()
+        .table_name(AGGREGATE_TABLE)
+        .item("uuid", AttributeValue::S(uuid.to_string()))
+        .item("window_key", AttributeValue::S(format!("{}{}", APPLIED_PREFIX, sequence_number)))
+        .item(TTL_ATTRIBUTE, AttributeValue::N((Utc::now().timestamp() + MARKER_TTL).to_string()))
+        .condition_expression("attribute_not_exists(window_key)")
+        .build();
+
+    let mut request = client
+        .transact_write_items()
+        .transact_items(TransactWriteItem::builder().put(marker).build());
+    for (window_key, retention_from, kind) in windows {
+        let update = add_update(uuid, window_key, *retention_from, *kind, values, retention_policy);
+        request = request.transact_items(TransactWriteItem::builder().update(update).build());
+    }
+
+    match request.send().await {
+        Ok(_out) => Ok(()),
+        Err(err) => {
+            // Only the marker has a condition, a failed check means the record was already counted
+            let already_applied = err
+                .as_service_error()
+                .and_then(|e| match e {
+                    TransactWriteItemsError::TransactionCanceledException(canceled) => canceled.cancellation_reasons(),
+                    _ => None,
+                })
+                .and_then(|reasons| reasons.first())
+                .and_then(|reason| reason.code())
+                == Some("ConditionalCheckFailed");
+            if already_applied {
+                Ok(())
+            } else {
+                Err(err.into())
+            }
+        }
+    }
+}
+
+/// DynamoDB has no min/max in update expressions, so they are conditional writes.
+/// Writing the same value again changes nothing, a retried record can go through here safely
+async fn update_min_max(client: &Client, uuid: &str, window_key: &str, values: &[(&str, f64)]) -> Result<(), Error> {
+    for (channel, value) in values {
+        for (attribute, operator) in [("min", ">"), ("max", "<")] {
+            let result = client
+                .update_item()
+                .table_name(AGGREGATE_TABLE)
+                .key("uuid", AttributeValue::S(uuid.to_string()))
+                .key("window_key", AttributeValue::S(window_key.to_string()))
+                .update_expression(format!("SET {}_{} = :value", channel, attribute))
+                .condition_expression(format!("attribute_not_exists({0}_{1}) OR {0}_{1} {2} :value", channel, attribute, operator))
+                .expression_attribute_values(":value", AttributeValue::N(value.to_string()))
+                .send()
+                .await;
+
+            if let Err(err) = result {
+                let not_changed = err.as_service_error().map(|e| e.is_conditional_check_failed_exception()) == Some(true);
+                if !not_changed {
+                    return Err(err.into());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn process_record(client: &Client, record: &StreamRecord) -> Result<(), Error> {
+    // A MODIFY is a reading written again by a retry of the ingest, it was already counted on INSERT
+    if record.event_name != "INSERT" || record.flag("outlier") {
+        return Ok(());
+    }
+    let (uuid, timestamp) = match (record.string("uuid"), record.number("timestamp")) {
+        (Some(uuid), Some(timestamp)) => (uuid, timestamp as i64),
+        _ => return Ok(()),
+    };
+    let values: Vec<(&str, f64)> = CHANNELS
+        .iter()
+        .filter_map(|channel| Some((*channel, record.number(channel)?)))
+        .collect();
+    if values.is_empty() {
+        return Ok(());
+    }
+
+    let (timezone, retention_policy) = get_sensor(client, uuid).await;
+    let windows = match window_keys(timestamp, timezone) {
+        Some(windows) => windows,
+        None => {
+            // Retrying can't fix the reading, skip it so the shard goes on
+            println!("Skipping reading of {} with invalid timestamp {}", uuid, timestamp);
+            return Ok(());
+        }
+    };
+    add_to_windows(client, uuid, &record.dynamodb.sequence_number, &windows, &values, &retention_policy).await?;
+    for (window_key, _, _) in windows.iter() {
+        update_min_max(client, uuid, window_key, &values).await?;
+    }
+    Ok(())
+}
+
+async fn function_handler(event: LambdaEvent<StreamEvent>) -> Result<BatchResponse, Error> {
+    let shared_config = aws_config::load_from_env().await;
+    let client = Client::new(&shared_config);
+
+    let mut response = BatchResponse::default();
+    for record in event.payload.records.iter() {
+        if let Err(err) = process_record(&client, record).await {
+            println!("{:?}", err);
+            // Records of a shard are in order, Lambda retries from this one. Records already counted are skipped by their marker
+            response.batch_item_failures.push(BatchItemFailure {
+                item_identifier: record.dynamodb.sequence_number.clone(),
+            });
+            break;
+        }
+    }
+    Ok(response)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::ERROR)
+        // disable printing the name of the module in every log line.
+        .with_target(false)
+        // disabling time is handy because CloudWatch will add the ingestion time.
+        .without_time()
+        .init();
+
+    run(service_fn(function_handler)).await
+}
@@ -0,0 +1,82 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/*
+    Example DynamoDB Stream event of the sensor_telemetry table (stream view NEW_IMAGE):
+
+    {
+        "Records": [
+            {
+                "eventName": "INSERT",
+                "dynamodb": {
+                    "SequenceNumber": "111",
+                    "NewImage": {
+                        "uuid": { "S": "297a0620-3b4d-40ed-b407-2216eb0d" },
+                        "timestamp": { "N": "1696834199" },
+                        "temperature": { "N": "24.5" },
+                        "humidity": { "N": "63.2" }
+                    }
+                }
+            }
+        ]
+    }
+*/
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct StreamEvent {
+    #[serde(rename = "Records")]
+    pub records: Vec<StreamRecord>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct StreamRecord {
+    #[serde(rename = "eventName")]
+    pub event_name: String,     // INSERT, MODIFY or REMOVE
+    pub dynamodb: StreamChange,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct StreamChange {
+    #[serde(rename = "SequenceNumber")]
+    pub sequence_number: String,
+    #[serde(rename = "NewImage", default)]
+    pub new_image: HashMap<String, StreamAttribute>,
+}
+
+/// Attribute of the image, only the types written by mqtt_month_media_processor
+#[derive(Deserialize, Clone, Debug)]
+pub struct StreamAttribute {
+    #[serde(rename = "S")]
+    pub s: Option<String>,
+    #[serde(rename = "N")]
+    pub n: Option<String>,
+    #[serde(rename = "BOOL")]
+    pub bool: Option<bool>,
+}
+
+impl StreamRecord {
+    pub fn string(&self, key: &str) -> Option<&str> {
+        self.dynamodb.new_image.get(key)?.s.as_deref()
+    }
+
+    pub fn number(&self, key: &str) -> Option<f64> {
+        self.dynamodb.new_image.get(key)?.n.as_ref()?.parse::<f64>().ok()
+    }
+
+    pub fn flag(&self, key: &str) -> bool {
+        self.dynamodb.new_image.get(key).and_then(|v| v.bool).unwrap_or(false)
+    }
+}
+
+/// Partial batch response, Lambda retries the stream from the first failed record
+#[derive(Serialize, Default)]
+pub struct BatchResponse {
+    #[serde(rename = "batchItemFailures")]
+    pub batch_item_failures: Vec<BatchItemFailure>,
+}
+
+#[derive(Serialize)]
+pub struct BatchItemFailure {
+    #[serde(rename = "itemIdentifier")]
+    pub item_identifier: String,
+}
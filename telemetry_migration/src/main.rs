@@ -0,0 +1,147 @@
+use aws_config::load_from_env;
+use aws_sdk_dynamodb::types::{AttributeValue, PutRequest, WriteRequest};
+use aws_sdk_dynamodb::Client;
+use std::collections::HashMap;
+use std::error::Error;
+use std::time::Duration;
+use tokio_stream::StreamExt;
+
+/*
+    Copy the old per-sensor tables (one table named after every sensor uuid)
+    and the old sensor_measuration table into sensor_telemetry.
+
+    cargo run --release -- --dry-run <uuid> <uuid>      # Show what would be copied
+    cargo run --release -- <uuid> sensor_measuration    # Copy these tables
+
+    The tables to copy must be listed, the account also has the tables of the other lambdas
+    and a table copied by mistake would fill sensor_telemetry with rows of unknown sensors.
+    Tables are read one page at a time, so big tables don't have to fit in memory.
+    Rollup rows of the old create_history_lambda (media_month or media_year, media_temp and
+    media_hum instead of temperature and humidity) are skipped: get_sensors_data reads only
+    readings from sensor_telemetry, create_history_lambda rebuilds the rollups from them.
+    Source tables are never modified, so the tool can be run again safely:
+    rows with the same uuid + timestamp are overwritten with the same data.
+*/
+
+const TELEMETRY_TABLE: &str = "sensor_telemetry";
+const BATCH_SIZE: usize = 25; // BatchWriteItem limit
+const MAX_RETRIES: u32 = 8;
+
+type Item = HashMap<String, AttributeValue>;
+
+fn is_flag_set(item: &Item, key: &str) -> bool {
+    match item.get(key) {
+        Some(AttributeValue::Bool(value)) => *value,
+        Some(AttributeValue::S(value)) => value == "true",
+        _ => false,
+    }
+}
+
+/// Raw readings only, rollups and rows without a timestamp can't go in the new table
+fn is_reading(item: &Item) -> bool {
+    item.contains_key("timestamp")
+        && item.contains_key("temperature")
+        && item.contains_key("humidity")
+        && !is_flag_set(item, "media_month")
+        && !is_flag_set(item, "media_year")
+}
+
+/// Old tables saved hour and media_month as "true"/"false" strings, readers expect booleans
+fn convert_item(table_name: &str, mut item: Item) -> Item {
+    if !item.contains_key("uuid") {
+        item.insert("uuid".to_string(), AttributeValue::S(table_name.to_string()));
+    }
+    for key in ["hour", "media_month", "media_year"] {
+        if let Some(AttributeValue::S(value)) = item.get(key) {
+            let value = value == "true";
+            item.insert(key.to_string(), AttributeValue::Bool(value));
+        }
+    }
+    item
+}
+
+async fn write_batch(client: &Client, items: Vec<Item>) -> Result<(), aws_sdk_dynamodb::Error> {
+    let mut requests: Vec<WriteRequest> = items
+        .into_iter()
+        .map(|item| {
+            WriteRequest::builder()
+                .put_request(PutRequest::builder().set_item(Some(item)).build())
+                .build()
+        })
+        .collect();
+
+    let mut retries = 0;
+    while !requests.is_empty() {
+        let output = client
+            .batch_write_item()
+            .request_items(TELEMETRY_TABLE, requests)
+            .send()
+            .await?;
+
+        requests = output
+            .unprocessed_items()
+            .and_then(|unprocessed| unprocessed.get(TELEMETRY_TABLE))
+            .cloned()
+            .unwrap_or_default();
+
+        if !requests.is_empty() {
+            retries += 1;
+            if retries > MAX_RETRIES {
+                panic!("Too many unprocessed items, try again later");
+            }
+            tokio::time::sleep(Duration::from_millis(100 * 2u64.pow(retries))).await; // Throttled, back off
+        }
+    }
+    Ok(())
+}
+
+/// Copied and skipped rows of the table
+async fn migrate_table(client: &Client, table_name: &str, dry_run: bool) -> Result<(usize, usize), aws_sdk_dynamodb::Error> {
+    let mut pages = client.scan().table_name(table_name).into_paginator().send();
+
+    let mut count = 0;
+    let mut skipped = 0;
+    while let Some(page) = pages.next().await {
+        let page = page?;
+        let rows = page.items().unwrap_or_default();
+        let items: Vec<Item> = rows
+            .iter()
+            .filter(|item| is_reading(item))
+            .map(|item| convert_item(table_name, item.clone()))
+            .collect();
+        skipped += rows.len() - items.len();
+        count += items.len();
+
+        if !dry_run {
+            for chunk in items.chunks(BATCH_SIZE) {
+                write_batch(client, chunk.to_vec()).await?;
+            }
+        }
+    }
+    Ok((count, skipped))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+    let tables: Vec<String> = args.into_iter().filter(|arg| arg != "--dry-run").collect();
+    if tables.is_empty() {
+        return Err("Usage: telemetry_migration [--dry-run] <table> [<table> ...]".into());
+    }
+    if let Some(table) = tables.iter().find(|table| table.as_str() == TELEMETRY_TABLE) {
+        return Err(format!("{} can't be copied into itself", table).into());
+    }
+
+    let shared_config = load_from_env().await;
+    let client = Client::new(&shared_config);
+
+    let mut total = 0;
+    for table in tables {
+        let (count, skipped) = migrate_table(&client, &table, dry_run).await?;
+        println!("{}: {} items {}, {} rows skipped", table, count, if dry_run { "to copy" } else { "copied" }, skipped);
+        total += count;
+    }
+    println!("Total: {} items", total);
+    Ok(())
+}